@@ -1,10 +1,16 @@
-use std::path::{Path, PathBuf};
+use std::path::{Path, PathBuf, Component};
+use std::collections::BTreeMap;
 
 use quire::validate::{Sequence, Structure, Enum, Nothing, Numeric, Scalar};
+use quire::validate::{Mapping};
 use quire::{parse_config, Options};
+#[cfg(test)]
+use quire::parse_string;
+use glob::Pattern;
 
 use version::Version;
 use bulk_version::MinimumVersion;
+use pack::convert::parse_relations;
 
 
 #[derive(Deserialize, Clone, Debug)]
@@ -13,6 +19,132 @@ pub struct Metadata {
     pub short_description: String,
     pub long_description: String,
     pub depends: Option<String>,
+    pub architecture: Option<String>,
+    pub maintainer: Option<String>,
+    pub section: Option<String>,
+    pub priority: Option<Priority>,
+    pub homepage: Option<String>,
+    pub pre_depends: Option<String>,
+    pub recommends: Option<String>,
+    pub suggests: Option<String>,
+    pub conflicts: Option<String>,
+    pub breaks: Option<String>,
+    pub replaces: Option<String>,
+    pub provides: Option<String>,
+    pub essential: bool,
+    pub multi_arch: Option<MultiArch>,
+    pub extra_fields: BTreeMap<String, String>,
+    pub scripts: Scripts,
+    pub conffiles: Conffiles,
+    pub permissions: Vec<Permissions>,
+    pub install: Vec<Install>,
+    pub dir: Option<PathBuf>,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub enum Priority {
+    required,
+    important,
+    standard,
+    optional,
+    extra,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub enum MultiArch {
+    same,
+    foreign,
+    allowed,
+    no,
+}
+
+/// Maintainer scripts, each is either a path to a file or an inline script
+#[derive(Deserialize, Clone, Debug)]
+pub struct Scripts {
+    pub preinst: Option<String>,
+    pub postinst: Option<String>,
+    pub prerm: Option<String>,
+    pub postrm: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Conffiles {
+    pub auto: bool,
+    pub add: Vec<PathBuf>,
+    pub exclude: Vec<PathBuf>,
+}
+
+/// Maps a path or a glob on the host to the destination in the package
+#[derive(Deserialize, Clone, Debug)]
+pub struct Install {
+    pub source: String,
+    pub dest: PathBuf,
+    pub mode: Option<String>,
+}
+
+/// Ownership and mode override for files matching a glob
+#[derive(Deserialize, Clone, Debug)]
+pub struct Permissions {
+    pub path: String,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub mode: Option<String>,
+    pub capabilities: Option<String>,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mtime {
+    fixed,
+    clamp,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    gzip,
+    xz,
+    zstd,
+    none,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    deb,
+    rpm,
+    arch,
+    apk,
+    tar,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    ignore,
+    warning,
+    error,
+}
+
+/// Options of `bulk pack` that aren't package metadata
+#[derive(Deserialize, Clone, Debug)]
+pub struct PackOptions {
+    pub formats: Vec<Format>,
+    pub compression: Compression,
+    pub compression_level: Option<u32>,
+    pub tar_prefix: Option<PathBuf>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub xattrs: bool,
+    pub mtime: Mtime,
+    pub dbgsym: bool,
+    pub auto_depends: bool,
+    pub shlibs: BTreeMap<String, String>,
+    pub lint: bool,
 }
 
 #[allow(non_camel_case_types)]
@@ -38,6 +170,9 @@ pub struct Repository {
 pub struct Config {
     pub minimum_bulk: Version<String>,
     pub metadata: Option<Metadata>,
+    pub packages: Vec<Metadata>,
+    pub pack: PackOptions,
+    pub lint: BTreeMap<String, Severity>,
     pub repositories: Vec<Repository>,
     pub versions: Vec<VersionHolder>,
 }
@@ -54,15 +189,95 @@ pub struct VersionHolder {
 }
 
 impl Config {
+    fn metadata_validator<'x>() -> Structure<'x> {
+        Structure::new()
+        .member("name", Scalar::new())
+        .member("short_description", Scalar::new())
+        .member("long_description", Scalar::new())
+        .member("depends", Scalar::new().optional())
+        .member("architecture", Scalar::new().optional())
+        .member("maintainer", Scalar::new().optional())
+        .member("section", Scalar::new().optional())
+        .member("priority", Enum::new().allow_plain().optional()
+            .option("required", Nothing)
+            .option("important", Nothing)
+            .option("standard", Nothing)
+            .option("optional", Nothing)
+            .option("extra", Nothing))
+        .member("homepage", Scalar::new().optional())
+        .member("pre_depends", Scalar::new().optional())
+        .member("recommends", Scalar::new().optional())
+        .member("suggests", Scalar::new().optional())
+        .member("conflicts", Scalar::new().optional())
+        .member("breaks", Scalar::new().optional())
+        .member("replaces", Scalar::new().optional())
+        .member("provides", Scalar::new().optional())
+        .member("essential", Scalar::new().default(false))
+        .member("multi_arch", Enum::new().allow_plain().optional()
+            .option("same", Nothing)
+            .option("foreign", Nothing)
+            .option("allowed", Nothing)
+            .option("no", Nothing))
+        .member("extra_fields", Mapping::new(Scalar::new(), Scalar::new()))
+        .member("scripts", Structure::new()
+            .member("preinst", Scalar::new().optional())
+            .member("postinst", Scalar::new().optional())
+            .member("prerm", Scalar::new().optional())
+            .member("postrm", Scalar::new().optional()))
+        .member("conffiles", Structure::new()
+            .member("auto", Scalar::new().default(true))
+            .member("add", Sequence::new(Scalar::new()))
+            .member("exclude", Sequence::new(Scalar::new())))
+        .member("permissions", Sequence::new(Structure::new()
+            .member("path", Scalar::new())
+            .member("user", Scalar::new().optional())
+            .member("group", Scalar::new().optional())
+            .member("uid", Numeric::new().optional())
+            .member("gid", Numeric::new().optional())
+            .member("mode", Scalar::new().optional())
+            .member("capabilities", Scalar::new().optional())))
+        .member("install", Sequence::new(Structure::new()
+            .member("source", Scalar::new())
+            .member("dest", Scalar::new())
+            .member("mode", Scalar::new().optional())))
+        .member("dir", Scalar::new().optional())
+    }
     fn validator<'x>() -> Structure<'x> {
         Structure::new()
         .member("minimum_bulk", MinimumVersion(
             Version(env!("CARGO_PKG_VERSION"))))
-        .member("metadata", Structure::new().optional()
-            .member("name", Scalar::new())
-            .member("short_description", Scalar::new())
-            .member("long_description", Scalar::new())
-            .member("depends", Scalar::new().optional()))
+        .member("metadata", Config::metadata_validator().optional())
+        .member("packages", Sequence::new(Config::metadata_validator()))
+        .member("pack", Structure::new()
+            .member("formats", Sequence::new(Enum::new().allow_plain()
+                .option("deb", Nothing)
+                .option("rpm", Nothing)
+                .option("arch", Nothing)
+                .option("apk", Nothing)
+                .option("tar", Nothing)))
+            .member("compression", Enum::new().allow_plain()
+                .option("gzip", Nothing)
+                .option("xz", Nothing)
+                .option("zstd", Nothing)
+                .option("none", Nothing)
+                .plain_default("gzip"))
+            .member("compression_level", Numeric::new().optional())
+            .member("tar_prefix", Scalar::new().optional())
+            .member("include", Sequence::new(Scalar::new()))
+            .member("exclude", Sequence::new(Scalar::new()))
+            .member("xattrs", Scalar::new().default(false))
+            .member("mtime", Enum::new().allow_plain()
+                .option("fixed", Nothing)
+                .option("clamp", Nothing)
+                .plain_default("fixed"))
+            .member("dbgsym", Scalar::new().default(false))
+            .member("auto_depends", Scalar::new().default(false))
+            .member("shlibs", Mapping::new(Scalar::new(), Scalar::new()))
+            .member("lint", Scalar::new().default(false)))
+        .member("lint", Mapping::new(Scalar::new(), Enum::new().allow_plain()
+            .option("error", Nothing)
+            .option("warning", Nothing)
+            .option("ignore", Nothing)))
         .member("repositories", Sequence::new(Structure::new()
             .member("kind", Enum::new().allow_plain()
                 .option("debian", Nothing)
@@ -82,8 +297,309 @@ impl Config {
             .member("regex", Scalar::new())
             .member("partial_version", Scalar::new().optional())))
     }
+    /// Returns metadata of all packages defined in the config
+    pub fn packages(&self) -> Vec<&Metadata> {
+        self.metadata.iter().chain(&self.packages).collect()
+    }
     pub fn parse_file(p: &Path) -> Result<Config, String> {
-        Ok(parse_config(p, &Config::validator(), &Options::default())
-            .map_err(|e| e.to_string())?)
+        let cfg: Config = parse_config(p,
+            &Config::validator(), &Options::default())
+            .map_err(|e| e.to_string())?;
+        cfg.check()
+    }
+    #[cfg(test)]
+    pub fn parse_string(data: &str) -> Result<Config, String> {
+        let cfg: Config = parse_string("<test>", data,
+            &Config::validator(), &Options::default())
+            .map_err(|e| e.to_string())?;
+        cfg.check()
+    }
+    /// Checks things that can't be expressed by the validator
+    fn check(self) -> Result<Config, String> {
+        let cfg = self;
+        if cfg.metadata.is_some() && cfg.packages.len() > 0 {
+            return Err(format!("Either `metadata` or `packages` must be \
+                specified in the config, not both"));
+        }
+        let packages = cfg.packages();
+        for (idx, meta) in packages.iter().enumerate() {
+            if packages[..idx].iter().any(|m| m.name == meta.name) {
+                return Err(format!("Duplicate package {:?}", meta.name));
+            }
+            check_relations(meta)?;
+            for &(name, ref value) in &[("maintainer", &meta.maintainer),
+                                        ("section", &meta.section),
+                                        ("homepage", &meta.homepage)] {
+                if let Some(ref value) = **value {
+                    check_single_line(name, value)?;
+                }
+            }
+            for (key, value) in &meta.extra_fields {
+                check_extra_field(key)?;
+                check_single_line(key, value)?;
+            }
+            for perm in &meta.permissions {
+                check_permissions(perm)?;
+            }
+            for item in &meta.install {
+                check_install(item)?;
+            }
+        }
+        if let Some(level) = cfg.pack.compression_level {
+            check_compression_level(cfg.pack.compression, level)?;
+        }
+        for pattern in cfg.pack.include.iter().chain(&cfg.pack.exclude) {
+            check_filter(pattern)?;
+        }
+        Ok(cfg)
+    }
+}
+
+/// Fields that are set by bulk itself or by other metadata options
+const KNOWN_FIELDS: &[&str] = &[
+    "Package", "Version", "Architecture", "Maintainer", "Installed-Size",
+    "Section", "Priority", "Homepage", "Essential", "Multi-Arch",
+    "Depends", "Pre-Depends", "Recommends", "Suggests", "Conflicts",
+    "Breaks", "Replaces", "Provides", "Description",
+];
+
+fn check_extra_field(name: &str) -> Result<(), String> {
+    if name.len() == 0 || name.starts_with('-') || name.starts_with('#') ||
+        !name.chars().all(|c| c.is_ascii_graphic() && c != ':')
+    {
+        return Err(format!("Invalid control field name {:?}", name));
+    }
+    if KNOWN_FIELDS.iter().any(|f| f.eq_ignore_ascii_case(name)) {
+        return Err(format!("Field {:?} can't be set in extra-fields, \
+            use respective metadata option instead", name));
+    }
+    Ok(())
+}
+
+/// Checks relation fields unless they contain variables
+///
+/// Variables are expanded when packing, so relations are checked again
+/// after expansion.
+pub fn check_relations(meta: &Metadata) -> Result<(), String> {
+    let fields = [
+        ("pre-depends", &meta.pre_depends),
+        ("depends", &meta.depends),
+        ("recommends", &meta.recommends),
+        ("suggests", &meta.suggests),
+        ("conflicts", &meta.conflicts),
+        ("breaks", &meta.breaks),
+        ("replaces", &meta.replaces),
+        ("provides", &meta.provides),
+    ];
+    for &(name, value) in fields.iter() {
+        if let Some(ref value) = *value {
+            if !value.contains("${") {
+                parse_relations(value).map_err(|e| format!(
+                    "Invalid {} of package {:?}: {}", name, meta.name, e))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that value doesn't span multiple lines, which would become
+/// continuation lines in the control file
+fn check_single_line(name: &str, value: &str) -> Result<(), String> {
+    if value.contains('\n') {
+        return Err(format!("Field {:?} must be a single line, got {:?}",
+                           name, value));
+    }
+    Ok(())
+}
+
+/// Checks compression level against the range supported by compressor
+pub fn check_compression_level(kind: Compression, level: u32)
+    -> Result<(), String>
+{
+    let (min, max) = match kind {
+        Compression::xz => (0, 9),
+        Compression::zstd => (1, 22),
+        Compression::gzip | Compression::none => {
+            return Err(format!("Compression level can't be set for {:?} \
+                compression, only for xz and zstd", kind));
+        }
+    };
+    if level < min || level > max {
+        return Err(format!("Compression level {} is out of range \
+            {}-{} for {:?}", level, min, max, kind));
+    }
+    Ok(())
+}
+
+/// Parses file mode in octal, with or without leading zero
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8).ok()
+        .and_then(|x| if x <= 0o7777 { Some(x) } else { None })
+        .ok_or_else(|| format!("Invalid file mode {:?}, \
+            octal number like 0755 expected", mode))
+}
+
+fn check_permissions(perm: &Permissions) -> Result<(), String> {
+    if !perm.path.starts_with('/') {
+        return Err(format!("Path {:?} in permissions must be absolute",
+                           perm.path));
+    }
+    Pattern::new(&perm.path)
+        .map_err(|e| format!("Invalid glob {:?}: {}", perm.path, e))?;
+    if let Some(ref mode) = perm.mode {
+        parse_mode(mode)?;
+    }
+    Ok(())
+}
+
+/// Checks include/exclude pattern: either an absolute path glob or a
+/// glob for a file name (without slashes)
+pub fn check_filter(pattern: &str) -> Result<(), String> {
+    if pattern.contains('/') && !pattern.starts_with('/') {
+        return Err(format!("Pattern {:?} must be either absolute or \
+            contain no slashes", pattern));
+    }
+    Pattern::new(pattern)
+        .map_err(|e| format!("Invalid glob {:?}: {}", pattern, e))?;
+    Ok(())
+}
+
+fn check_install(item: &Install) -> Result<(), String> {
+    if !item.dest.is_absolute() ||
+        item.dest.components().any(|c| c == Component::ParentDir)
+    {
+        return Err(format!("Install destination {:?} must be an absolute \
+            path without `..`", item.dest));
+    }
+    if let Some(ref mode) = item.mode {
+        parse_mode(mode)?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::Config;
+
+    #[test]
+    fn two_packages() {
+        let cfg = Config::parse_string(r#"
+            minimum-bulk: v0.4.5
+            packages:
+            - name: foo
+              short-description: the foo
+              long-description: the foo daemon
+              depends: foo-common
+              dir: pkg/foo
+            - name: foo-common
+              short-description: common files of foo
+              long-description: common files of the foo daemon
+              architecture: all
+        "#).unwrap();
+        let names = cfg.packages().iter().map(|m| &m.name[..])
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["foo", "foo-common"]);
+        assert_eq!(cfg.packages[0].depends,
+                   Some(String::from("foo-common")));
+        assert_eq!(cfg.packages[1].architecture, Some(String::from("all")));
+    }
+
+    #[test]
+    fn metadata_and_packages() {
+        let err = Config::parse_string(r#"
+            minimum-bulk: v0.4.5
+            metadata:
+              name: foo
+              short-description: the foo
+              long-description: the foo daemon
+            packages:
+            - name: bar
+              short-description: the bar
+              long-description: the bar daemon
+        "#).unwrap_err();
+        assert!(err.starts_with("Either `metadata` or `packages`"));
+    }
+
+    #[test]
+    fn duplicate_package() {
+        let err = Config::parse_string(r#"
+            minimum-bulk: v0.4.5
+            packages:
+            - name: foo
+              short-description: the foo
+              long-description: the foo daemon
+            - name: foo
+              short-description: another foo
+              long-description: another foo daemon
+        "#).unwrap_err();
+        assert_eq!(err, r#"Duplicate package "foo""#);
+    }
+
+    #[test]
+    fn relations() {
+        let err = Config::parse_string(r#"
+            minimum-bulk: v0.4.5
+            metadata:
+              name: foo
+              short-description: the foo
+              long-description: the foo daemon
+              depends: libc6 (>= 2.0), libbar (1.0)
+        "#).unwrap_err();
+        assert!(err.starts_with(r#"Invalid depends of package "foo""#),
+                "{}", err);
+        // Variables are checked after expansion
+        assert!(Config::parse_string(r#"
+            minimum-bulk: v0.4.5
+            metadata:
+              name: foo
+              short-description: the foo
+              long-description: the foo daemon
+              depends: foo-data (= ${version})
+        "#).is_ok());
+    }
+
+    #[test]
+    fn multiline_fields() {
+        let err = Config::parse_string(r#"
+            minimum-bulk: v0.4.5
+            metadata:
+              name: foo
+              short-description: the foo
+              long-description: the foo daemon
+              maintainer: "Jane Doe\nDepends: evil"
+        "#).unwrap_err();
+        assert!(err.starts_with(r#"Field "maintainer" must be a single"#),
+                "{}", err);
+        let err = Config::parse_string(r#"
+            minimum-bulk: v0.4.5
+            metadata:
+              name: foo
+              short-description: the foo
+              long-description: the foo daemon
+              extra-fields:
+                X-Notes: "one\ntwo"
+        "#).unwrap_err();
+        assert!(err.starts_with(r#"Field "X-Notes" must be a single"#),
+                "{}", err);
+    }
+
+    #[test]
+    fn compression_level() {
+        let config = |compression, level| Config::parse_string(&format!("
+            minimum-bulk: v0.4.5
+            pack:
+              compression: {}
+              compression-level: {}
+        ", compression, level));
+        assert!(config("xz", 0).is_ok());
+        assert!(config("zstd", 22).is_ok());
+        assert_eq!(config("xz", 10).unwrap_err(),
+                   "Compression level 10 is out of range 0-9 for xz");
+        assert_eq!(config("zstd", 0).unwrap_err(),
+                   "Compression level 0 is out of range 1-22 for zstd");
+        assert_eq!(config("gzip", 9).unwrap_err(),
+                   "Compression level can't be set for gzip compression, \
+                    only for xz and zstd");
     }
 }
@@ -2,9 +2,37 @@ use std::io::{self, Read};
 use std::fs::File;
 use std::path::Path;
 
+use md5;
 use sha2::{Sha256, Digest};
 
 
+/// Computes md5 sum of the data while it's being read
+pub struct Md5Reader<R: Read> {
+    inner: R,
+    md5: md5::Context,
+}
+
+impl<R: Read> Md5Reader<R> {
+    pub fn new(inner: R) -> Md5Reader<R> {
+        Md5Reader {
+            inner: inner,
+            md5: md5::Context::new(),
+        }
+    }
+    pub fn digest(self) -> md5::Digest {
+        self.md5.compute()
+    }
+}
+
+impl<R: Read> Read for Md5Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = try!(self.inner.read(buf));
+        self.md5.consume(&buf[..bytes]);
+        Ok(bytes)
+    }
+}
+
+
 pub fn hash_stream<D: Digest, R: Read>(hash: &mut D, reader: &mut R)
     -> Result<(), io::Error>
 {
@@ -1,16 +1,22 @@
 extern crate argparse;
 extern crate env_logger;
 extern crate libflate;
+extern crate md5;
 extern crate git2;
+extern crate glob;
 extern crate quire;
 extern crate regex;
 extern crate serde;
+extern crate serde_json;
 extern crate scan_dir;
 extern crate sha2;
 extern crate tar;
 extern crate tempfile;
 extern crate time;
 extern crate unicase;
+extern crate xattr;
+extern crate xz2;
+extern crate zstd;
 #[macro_use] extern crate failure;
 #[macro_use] extern crate log;
 #[macro_use] extern crate matches;
@@ -39,6 +45,10 @@ enum Action {
     Help,
     Pack,
     RepoAdd,
+    Inspect,
+    Unpack,
+    Diff,
+    Lint,
     GetVersion,
     SetVersion,
     IncrVersion,
@@ -63,6 +73,11 @@ impl FromStr for Action {
             "add_to_repo" => Ok(Action::RepoAdd),
             "addtorepo" => Ok(Action::RepoAdd),
 
+            "inspect" => Ok(Action::Inspect),
+            "unpack" => Ok(Action::Unpack),
+            "diff" => Ok(Action::Diff),
+            "lint" => Ok(Action::Lint),
+
             "getversion" => Ok(Action::GetVersion),
             "get-version" => Ok(Action::GetVersion),
             "getver" => Ok(Action::GetVersion),
@@ -115,8 +130,9 @@ fn main() {
         ap.refer(&mut command)
             .add_argument("command", Store, "
                 Command to run. Supported commands: \
-                pack, repo-add, get-version, set-version, incr-version, \
-                check-version, with-version, with-git-version");
+                pack, repo-add, inspect, unpack, diff, lint, get-version, \
+                set-version, incr-version, check-version, with-version, \
+                with-git-version");
         ap.refer(&mut args)
             .add_argument("arguments", List,
                 "Arguments for the command");
@@ -128,8 +144,8 @@ fn main() {
         Action::Help => {
             println!("Usage:");
             println!("    bulk \
-                {{pack,repo-add,get-version,set-version,\
-                  check-version,with-version,with-git-version}} \
+                {{pack,repo-add,inspect,unpack,diff,lint,get-version,\
+                  set-version,check-version,with-version,with-git-version}} \
                 [options]");
         }
         Action::Pack => {
@@ -140,6 +156,22 @@ fn main() {
             args.insert(0, "bulk repo-add".to_string());
             repo::repo_add(args);
         }
+        Action::Inspect => {
+            args.insert(0, "bulk inspect".to_string());
+            repo::inspect(args);
+        }
+        Action::Unpack => {
+            args.insert(0, "bulk unpack".to_string());
+            repo::unpack(args);
+        }
+        Action::Diff => {
+            args.insert(0, "bulk diff".to_string());
+            repo::diff(args);
+        }
+        Action::Lint => {
+            args.insert(0, "bulk lint".to_string());
+            repo::lint(args);
+        }
         Action::GetVersion => {
             args.insert(0, "bulk get-version".to_string());
             ver::get_version(args);
@@ -0,0 +1,286 @@
+use std::io::{self, Write, Seek, SeekFrom, BufWriter};
+use std::path::Path;
+
+use sha2::{Sha256, Digest};
+use tar::{Builder as Archive};
+use tempfile::tempfile_in;
+
+use config::Metadata;
+use hash_file::hash_stream;
+use super::compress::gzip_encoder;
+use super::convert::{parse_simple_relations, split_version, Relation};
+use super::deb::{read_scripts, script_call};
+use super::files::Entry;
+use super::tar::ArchiveExt;
+
+
+/// Converts debian architecture name to the one used in Alpine
+fn apk_arch(arch: &str) -> &str {
+    match arch {
+        "all" => "noarch",
+        "amd64" => "x86_64",
+        "i386" => "x86",
+        "arm64" => "aarch64",
+        "armhf" => "armv7",
+        "ppc64el" => "ppc64le",
+        _ => arch,
+    }
+}
+
+/// Returns version and numeric release as used in Alpine (`1.0-r3`)
+///
+/// Epoch and everything in release except leading digits is dropped,
+/// release is zero if there is no dash in a version.
+fn apk_version(version: &str) -> String {
+    let (_, ver, rel) = split_version(version);
+    let rel = if version.contains('-') {
+        rel.split(|c: char| !c.is_digit(10)).next().unwrap()
+            .parse::<u64>().unwrap_or(0)
+    } else {
+        0
+    };
+    format!("{}-r{}", ver, rel)
+}
+
+/// Returns file name of the package (without directory)
+pub fn package_name(meta: &Metadata, version: &str) -> String {
+    format!("{}-{}.apk", meta.name, apk_version(version))
+}
+
+fn relations(field: &str, value: &Option<String>)
+    -> io::Result<Vec<Relation>>
+{
+    match *value {
+        Some(ref value) => parse_simple_relations(value, field, "apk")
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData,
+                format!("can't convert {} to apk: {}", field, e))),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Writes Alpine package (`.apk`)
+///
+/// The package is a concatenation of gzipped control and data archives,
+/// signature is not included so the package must be installed with
+/// `--allow-untrusted` or signed with `abuild-sign` later.
+///
+/// Debian maintainer scripts are wrapped into apk hooks, which pass them
+/// arguments the same way `dpkg` does.
+pub fn write_apk<W: Write>(out: W, tmpdir: &Path, files: &[Entry],
+    meta: &Metadata, version: &str, architecture: &str, mtime: u32)
+    -> Result<(), io::Error>
+{
+    let scripts = try!(read_scripts(&meta.scripts));
+
+    // Data archive is written first because control archive has its hash
+    let mut data_file = try!(tempfile_in(tmpdir));
+    let mut size = 0;
+    {
+        let mut dreal = gzip_encoder(&mut data_file)?;
+        {
+            let mut arch = Archive::new(&mut dreal);
+            for entry in files {
+                if let Some(sum) = arch.append_entry(entry)? {
+                    size += sum.size;
+                }
+            }
+            arch.finish()?;
+        }
+        dreal.finish().into_result()?;
+    }
+    try!(data_file.seek(SeekFrom::Start(0)));
+    let mut sha256 = Sha256::new();
+    try!(hash_stream(&mut sha256, &mut data_file));
+    try!(data_file.seek(SeekFrom::Start(0)));
+
+    let mut pkginfo = Vec::new();
+    writeln!(&mut pkginfo, "# Generated by bulk {}",
+        env!("CARGO_PKG_VERSION"))?;
+    writeln!(&mut pkginfo, "pkgname = {}", meta.name)?;
+    writeln!(&mut pkginfo, "pkgver = {}", apk_version(version))?;
+    writeln!(&mut pkginfo, "pkgdesc = {}", meta.short_description)?;
+    if let Some(ref homepage) = meta.homepage {
+        writeln!(&mut pkginfo, "url = {}", homepage)?;
+    }
+    writeln!(&mut pkginfo, "builddate = {}", mtime)?;
+    if let Some(ref maintainer) = meta.maintainer {
+        writeln!(&mut pkginfo, "packager = {}", maintainer)?;
+    }
+    writeln!(&mut pkginfo, "size = {}", size)?;
+    writeln!(&mut pkginfo, "arch = {}", apk_arch(architecture))?;
+    writeln!(&mut pkginfo, "origin = {}", meta.name)?;
+    if let Some(ref maintainer) = meta.maintainer {
+        writeln!(&mut pkginfo, "maintainer = {}", maintainer)?;
+    }
+    for rel in try!(relations("replaces", &meta.replaces)) {
+        writeln!(&mut pkginfo, "replaces = {}", rel.name)?;
+    }
+    for rel in try!(relations("provides", &meta.provides)) {
+        writeln!(&mut pkginfo, "provides = {}", rel.compact())?;
+    }
+    for rel in try!(relations("pre-depends", &meta.pre_depends)).into_iter()
+        .chain(try!(relations("depends", &meta.depends)))
+    {
+        writeln!(&mut pkginfo, "depend = {}", rel.compact())?;
+    }
+    for rel in try!(relations("conflicts", &meta.conflicts)).into_iter()
+        .chain(try!(relations("breaks", &meta.breaks)))
+    {
+        writeln!(&mut pkginfo, "depend = !{}", rel.compact())?;
+    }
+    writeln!(&mut pkginfo, "datahash = {:x}", sha256.result())?;
+
+    let mut control = Vec::new();
+    {
+        let mut arch = Archive::new(&mut control);
+        arch.append_blob(".PKGINFO", mtime, 0o644, &pkginfo)?;
+        for &(name, ref data) in &scripts {
+            // apk passes new version as `$1` and old one as `$2`
+            let hooks: &[(&str, &str)] = match name {
+                "preinst" => &[(".pre-install", "install"),
+                               (".pre-upgrade", "upgrade \"$2\"")],
+                "postinst" => &[(".post-install", "configure"),
+                                (".post-upgrade", "configure \"$2\"")],
+                "prerm" => &[(".pre-deinstall", "remove")],
+                "postrm" => &[(".post-deinstall", "remove")],
+                _ => unreachable!(),
+            };
+            for &(hook, args) in hooks {
+                let mut body = b"#!/bin/sh\n".to_vec();
+                body.extend(script_call(args, data));
+                arch.append_blob(hook, mtime, 0o755, &body)?;
+            }
+        }
+        arch.finish()?;
+    }
+    // Control archive is concatenated with data archive, so it must not
+    // contain end-of-archive marker (two zero blocks)
+    let control_len = control.len() - 1024;
+    control.truncate(control_len);
+
+    let mut out = BufWriter::new(out);
+    {
+        let mut creal = gzip_encoder(&mut out)?;
+        creal.write_all(&control)?;
+        creal.finish().into_result()?;
+    }
+    try!(io::copy(&mut data_file, &mut out));
+    try!(out.flush());
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::fs::{File, create_dir_all};
+    use std::io::{Read, Write};
+    use std::path::Path;
+    use sha2::{Sha256, Digest};
+    use tar;
+    use tempfile::tempdir;
+
+    use config::{Config, Metadata};
+    use pack::compress::decompress;
+    use pack::files::{self, Filter};
+    use super::write_apk;
+
+    fn metadata() -> Metadata {
+        Config::parse_string("minimum-bulk: v0.4.5\n\
+            metadata:\n  \
+              name: hello\n  \
+              short-description: hello world\n  \
+              long-description: prints hello world\n  \
+              depends: libc6 (>= 2.0)\n")
+            .unwrap().metadata.unwrap()
+    }
+
+    fn tar_file(data: &[u8], name: &str) -> Option<Vec<u8>> {
+        let mut arch = tar::Archive::new(data);
+        for entry in arch.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry.path().unwrap().to_str() == Some(name) {
+                let mut buf = Vec::new();
+                entry.read_to_end(&mut buf).unwrap();
+                return Some(buf);
+            }
+        }
+        None
+    }
+
+    fn apk(meta: &Metadata, dir: &Path) -> Vec<u8> {
+        let tmp = tempdir().unwrap();
+        let options = files::Options {
+            filter: Filter::new(&[], &[]).unwrap(),
+            xattrs: false,
+            mtime: 1,
+            clamp_mtime: None,
+        };
+        let files = files::collect(dir, meta, &options).unwrap();
+        let mut buf = Vec::new();
+        write_apk(&mut buf, tmp.path(), &files, meta, "1.0-2", "amd64", 1)
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn package() {
+        let meta = metadata();
+        let tmp = tempdir().unwrap();
+        create_dir_all(tmp.path().join("usr/bin")).unwrap();
+        create_dir_all(tmp.path().join("etc")).unwrap();
+        File::create(tmp.path().join("usr/bin/hello")).unwrap()
+            .write_all(b"hello").unwrap();
+        File::create(tmp.path().join("etc/hello.conf")).unwrap()
+            .write_all(b"greeting=hello\n").unwrap();
+        let buf = apk(&meta, tmp.path());
+
+        // Data archive is the second gzip stream (after the 10-byte header
+        // of the first one)
+        let split = (10..buf.len())
+            .find(|&i| buf[i..].starts_with(b"\x1f\x8b\x08"))
+            .unwrap();
+        let control = decompress(&buf[..split]).unwrap();
+        assert_eq!(control.len() % 512, 0);
+        assert!(!control.ends_with(&[0; 1024][..]));
+
+        let pkginfo = String::from_utf8(tar_file(&control, ".PKGINFO")
+                                        .unwrap()).unwrap();
+        let mut sha256 = Sha256::new();
+        sha256.input(&buf[split..]);
+        assert!(pkginfo.contains("\npkgver = 1.0-r2\n"), "{}", pkginfo);
+        assert!(pkginfo.contains("\nsize = 20\n"), "{}", pkginfo);
+        assert!(pkginfo.contains("\ndepend = libc6>=2.0\n"), "{}", pkginfo);
+        assert!(pkginfo.ends_with(
+            &format!("\ndatahash = {:x}\n", sha256.result())));
+
+        // Concatenated streams are a single archive with control first
+        let whole = decompress(&buf).unwrap();
+        assert!(tar_file(&whole, ".PKGINFO").is_some());
+        assert_eq!(tar_file(&whole, "usr/bin/hello").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn scripts() {
+        let mut meta = metadata();
+        meta.scripts.postinst = Some(String::from("#!/bin/sh -e\nldconfig"));
+        meta.scripts.prerm = Some(String::from("#!/bin/sh\ntrue\n"));
+        let tmp = tempdir().unwrap();
+        let control = decompress(&apk(&meta, tmp.path())).unwrap();
+        let text = |name| String::from_utf8(tar_file(&control, name)
+                                             .unwrap()).unwrap();
+        assert_eq!(text(".post-install"), "#!/bin/sh\n\
+            /bin/sh -e /dev/stdin configure <<'__BULK_SCRIPT_END__'\n\
+            #!/bin/sh -e\nldconfig\n\
+            __BULK_SCRIPT_END__\n");
+        assert_eq!(text(".post-upgrade"), "#!/bin/sh\n\
+            /bin/sh -e /dev/stdin configure \"$2\" \
+                <<'__BULK_SCRIPT_END__'\n\
+            #!/bin/sh -e\nldconfig\n\
+            __BULK_SCRIPT_END__\n");
+        assert_eq!(text(".pre-deinstall"), "#!/bin/sh\n\
+            /bin/sh /dev/stdin remove <<'__BULK_SCRIPT_END__'\n\
+            #!/bin/sh\ntrue\n\
+            __BULK_SCRIPT_END__\n");
+        assert!(tar_file(&control, ".pre-install").is_none());
+    }
+}
@@ -0,0 +1,179 @@
+/// Names of capabilities in the order of their numbers (`cap_` is omitted)
+const NAMES: &[&str] = &[
+    "chown", "dac_override", "dac_read_search", "fowner", "fsetid", "kill",
+    "setgid", "setuid", "setpcap", "linux_immutable", "net_bind_service",
+    "net_broadcast", "net_admin", "net_raw", "ipc_lock", "ipc_owner",
+    "sys_module", "sys_rawio", "sys_chroot", "sys_ptrace", "sys_pacct",
+    "sys_admin", "sys_boot", "sys_nice", "sys_resource", "sys_time",
+    "sys_tty_config", "mknod", "lease", "audit_write", "audit_control",
+    "setfcap", "mac_override", "mac_admin", "syslog", "wake_alarm",
+    "block_suspend", "audit_read", "perfmon", "bpf", "checkpoint_restore",
+];
+
+const VFS_CAP_REVISION_MASK: u32 = 0xff000000;
+const VFS_CAP_REVISION_2: u32 = 0x02000000;
+// Same as version 2 but has root uid of the user namespace at the end
+const VFS_CAP_REVISION_3: u32 = 0x03000000;
+const VFS_CAP_FLAGS_EFFECTIVE: u32 = 0x000001;
+
+/// Name of the extended attribute that holds file capabilities
+pub const XATTR_NAME: &str = "security.capability";
+
+
+fn capability(name: &str) -> Result<u64, String> {
+    let lower = name.to_lowercase();
+    let short = if lower.starts_with("cap_") { &lower[4..] } else { &lower };
+    if short == "all" {
+        return Ok((1 << NAMES.len()) - 1);
+    }
+    NAMES.iter().position(|x| *x == short)
+        .map(|idx| 1 << idx)
+        .ok_or_else(|| format!("unknown capability {:?}", name))
+}
+
+/// Converts capabilities in the textual form of `setcap` (for example
+/// `cap_net_bind_service,cap_net_raw=ep`) into the value of
+/// `security.capability` extended attribute
+pub fn encode(text: &str) -> Result<Vec<u8>, String> {
+    let mut effective = 0u64;
+    let mut permitted = 0u64;
+    let mut inheritable = 0u64;
+    for clause in text.split_whitespace() {
+        let pos = clause.find(|c| c == '=' || c == '+' || c == '-')
+            .ok_or_else(|| format!("no operator in {:?}", clause))?;
+        let mut mask = 0u64;
+        for name in clause[..pos].split(',') {
+            mask |= if name.len() == 0 && pos == 0 {
+                capability("all")?
+            } else {
+                capability(name)?
+            };
+        }
+        let mut ops = &clause[pos..];
+        while ops.len() > 0 {
+            let op = ops.as_bytes()[0];
+            let end = ops[1..].find(|c| c == '=' || c == '+' || c == '-')
+                .map(|x| x + 1).unwrap_or(ops.len());
+            let flags = &ops[1..end];
+            if op == b'=' {
+                effective &= !mask;
+                permitted &= !mask;
+                inheritable &= !mask;
+            }
+            for flag in flags.chars() {
+                let set = match flag {
+                    'e' => &mut effective,
+                    'p' => &mut permitted,
+                    'i' => &mut inheritable,
+                    _ => return Err(format!("unknown flag {:?} in {:?}",
+                                            flag, clause)),
+                };
+                if op == b'-' {
+                    *set &= !mask;
+                } else {
+                    *set |= mask;
+                }
+            }
+            ops = &ops[end..];
+        }
+    }
+    let magic = VFS_CAP_REVISION_2 |
+        if effective != 0 { VFS_CAP_FLAGS_EFFECTIVE } else { 0 };
+    let mut result = Vec::with_capacity(20);
+    for &word in &[magic,
+                   permitted as u32, inheritable as u32,
+                   (permitted >> 32) as u32, (inheritable >> 32) as u32]
+    {
+        result.extend(&[word as u8, (word >> 8) as u8,
+                        (word >> 16) as u8, (word >> 24) as u8]);
+    }
+    Ok(result)
+}
+
+/// Converts value of `security.capability` back into the textual form
+///
+/// Returns `None` if value is not in a known format.
+pub fn decode(value: &[u8]) -> Option<String> {
+    if value.len() != 20 && value.len() != 24 {
+        return None;
+    }
+    let word = |idx: usize| {
+        value[idx*4..idx*4+4].iter().rev()
+            .fold(0u64, |acc, &b| (acc << 8) | b as u64)
+    };
+    let magic = word(0) as u32;
+    match magic & VFS_CAP_REVISION_MASK {
+        VFS_CAP_REVISION_2 | VFS_CAP_REVISION_3 => {}
+        _ => return None,
+    }
+    let effective = magic & VFS_CAP_FLAGS_EFFECTIVE != 0;
+    let permitted = word(1) | (word(3) << 32);
+    let inheritable = word(2) | (word(4) << 32);
+    // Capabilities are grouped by their flags as `setcap` does
+    let mut groups: Vec<(String, Vec<&str>)> = Vec::new();
+    for (idx, name) in NAMES.iter().enumerate() {
+        let mut flags = String::new();
+        if effective && (permitted | inheritable) & (1 << idx) != 0 {
+            flags.push('e');
+        }
+        if inheritable & (1 << idx) != 0 {
+            flags.push('i');
+        }
+        if permitted & (1 << idx) != 0 {
+            flags.push('p');
+        }
+        if flags.len() == 0 {
+            continue;
+        }
+        match groups.iter().position(|&(ref f, _)| *f == flags) {
+            Some(pos) => groups[pos].1.push(name),
+            None => groups.push((flags, vec![name])),
+        }
+    }
+    Some(groups.iter().map(|&(ref flags, ref names)| {
+        format!("{}={}", names.iter().map(|n| format!("cap_{}", n))
+                .collect::<Vec<_>>().join(","), flags)
+    }).collect::<Vec<_>>().join(" "))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{encode, decode};
+
+    #[test]
+    fn bind_service() {
+        assert_eq!(encode("cap_net_bind_service=ep").unwrap(),
+            vec![1, 0, 0, 2, 0, 4, 0, 0, 0, 0, 0, 0,
+                 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(encode("CAP_NET_BIND_SERVICE+p").unwrap(),
+            vec![0, 0, 0, 2, 0, 4, 0, 0, 0, 0, 0, 0,
+                 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn multiple() {
+        assert_eq!(encode("cap_chown,cap_kill+ei cap_bpf+p").unwrap(),
+            vec![1, 0, 0, 2, 0, 0, 0, 0, 0x21, 0, 0, 0,
+                 0x80, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(encode("all=p cap_chown-p").unwrap()[4..8].to_vec(),
+            vec![0xfe, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn roundtrip() {
+        assert_eq!(decode(&encode("cap_net_bind_service=ep").unwrap()),
+            Some(String::from("cap_net_bind_service=ep")));
+        assert_eq!(decode(&encode("cap_chown,cap_kill+ei cap_bpf+p")
+                          .unwrap()),
+            Some(String::from("cap_chown,cap_kill=ei cap_bpf=ep")));
+        assert_eq!(decode(b"xxx"), None);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(encode("cap_foo=ep").is_err());
+        assert!(encode("cap_chown").is_err());
+        assert!(encode("cap_chown=x").is_err());
+    }
+}
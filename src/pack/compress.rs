@@ -0,0 +1,355 @@
+use std::io::{self, Read, BufRead, Write};
+use std::mem;
+use std::str::FromStr;
+use std::thread::{self, JoinHandle};
+use std::collections::VecDeque;
+
+use libflate::gzip;
+use xz2::read::XzDecoder;
+use xz2::stream::{MtStreamBuilder, Check};
+use xz2::write::XzEncoder;
+use zstd;
+
+use config::Compression;
+
+
+/// Size of input chunks that are compressed independently when multiple
+/// threads are used (it's also a block size of xz)
+///
+/// Output depends on this size, so it must never depend on the number of
+/// threads or on the machine, or packages would not be reproducible.
+const CHUNK_SIZE: usize = 1 << 20;
+
+
+/// Compression method with an optional level
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    pub kind: Compression,
+    pub level: Option<u32>,
+    /// Number of threads, single stream is written when it's `1`
+    pub jobs: usize,
+}
+
+pub enum Encoder<W: Write> {
+    Gzip(gzip::Encoder<W>),
+    Xz(XzEncoder<W>),
+    Zstd(zstd::Encoder<W>),
+    Parallel(Parallel<W>),
+    Plain(W),
+}
+
+/// Encoder that splits input into chunks and gzips them in threads
+///
+/// Each chunk is a separate gzip member, concatenated they form a valid
+/// stream which is decompressed by usual tools (like `pigz` does). The
+/// output is the same for any number of threads above one.
+pub struct Parallel<W: Write> {
+    settings: Settings,
+    out: W,
+    chunk: Vec<u8>,
+    running: VecDeque<JoinHandle<io::Result<Vec<u8>>>>,
+    chunks: usize,
+}
+
+pub enum Decoder<R: BufRead> {
+    Gzip(gzip::MultiDecoder<R>),
+    Xz(XzDecoder<R>),
+    Zstd(zstd::Decoder<R>),
+    Plain(R),
+}
+
+impl FromStr for Compression {
+    type Err = ();
+    fn from_str(value: &str) -> Result<Compression, ()> {
+        match value {
+            "gzip" | "gz" => Ok(Compression::gzip),
+            "xz" => Ok(Compression::xz),
+            "zstd" | "zst" => Ok(Compression::zstd),
+            "none" => Ok(Compression::none),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Settings {
+    /// Extension of the compressed file including the dot
+    pub fn extension(&self) -> &'static str {
+        match self.kind {
+            Compression::gzip => ".gz",
+            Compression::xz => ".xz",
+            Compression::zstd => ".zst",
+            Compression::none => "",
+        }
+    }
+    /// Compression level that is actually used, including defaults
+    pub fn level(&self) -> u32 {
+        match self.kind {
+            Compression::xz => self.level.unwrap_or(6),
+            Compression::zstd => self.level.unwrap_or(3),
+            // libflate has no levels, its default is close to zlib's 6
+            Compression::gzip => 6,
+            Compression::none => 0,
+        }
+    }
+    /// Returns encoder writing to `out`
+    ///
+    /// Gzip compression level isn't configurable
+    pub fn encoder<W: Write>(&self, out: W) -> io::Result<Encoder<W>> {
+        match self.kind {
+            Compression::gzip if self.jobs > 1 => {
+                Ok(Encoder::Parallel(Parallel {
+                    settings: *self,
+                    out: out,
+                    chunk: Vec::with_capacity(CHUNK_SIZE),
+                    running: VecDeque::with_capacity(self.jobs),
+                    chunks: 0,
+                }))
+            }
+            Compression::gzip => Ok(Encoder::Gzip(gzip_encoder(out)?)),
+            // Xz and zstd libraries have their own multi-threaded
+            // encoders which write a single stream of independent blocks.
+            // Concatenated streams would not be supported by dpkg.
+            Compression::xz if self.jobs > 1 => {
+                let stream = MtStreamBuilder::new()
+                    .threads(self.jobs as u32)
+                    .block_size(CHUNK_SIZE as u64)
+                    .preset(self.level())
+                    .check(Check::Crc64)
+                    .encoder()?;
+                Ok(Encoder::Xz(XzEncoder::new_stream(out, stream)))
+            }
+            Compression::xz => Ok(Encoder::Xz(
+                XzEncoder::new(out, self.level()))),
+            Compression::zstd => {
+                let mut enc = zstd::Encoder::new(out, self.level() as i32)?;
+                if self.jobs > 1 {
+                    enc.multithread(self.jobs as u32)?;
+                }
+                Ok(Encoder::Zstd(enc))
+            }
+            Compression::none => Ok(Encoder::Plain(out)),
+        }
+    }
+}
+
+/// Returns gzip encoder with zero modification time in the header
+///
+/// By default current time is written, which makes the result
+/// irreproducible.
+pub fn gzip_encoder<W: Write>(out: W) -> io::Result<gzip::Encoder<W>> {
+    let header = gzip::HeaderBuilder::new().modification_time(0).finish();
+    gzip::Encoder::with_options(out,
+        gzip::EncodeOptions::new().header(header))
+}
+
+impl<W: Write> Parallel<W> {
+    /// Starts compressing current chunk in a thread
+    ///
+    /// If all threads are busy, waits for the oldest one first.
+    fn spawn(&mut self) -> io::Result<()> {
+        if self.running.len() >= self.settings.jobs {
+            self.write_oldest()?;
+        }
+        let chunk = mem::replace(&mut self.chunk,
+                                 Vec::with_capacity(CHUNK_SIZE));
+        let settings = Settings { jobs: 1, .. self.settings };
+        self.running.push_back(thread::spawn(move || {
+            let mut enc = settings.encoder(Vec::new())?;
+            enc.write_all(&chunk)?;
+            enc.finish()
+        }));
+        self.chunks += 1;
+        Ok(())
+    }
+    /// Waits for the oldest chunk and writes it, so the order is kept
+    fn write_oldest(&mut self) -> io::Result<()> {
+        if let Some(handle) = self.running.pop_front() {
+            let data = handle.join().map_err(|_| {
+                io::Error::new(io::ErrorKind::Other,
+                               "compression thread panicked")
+            })??;
+            self.out.write_all(&data)?;
+        }
+        Ok(())
+    }
+    fn finish(mut self) -> io::Result<W> {
+        // Empty input is still written as a single (empty) member
+        if self.chunk.len() > 0 || self.chunks == 0 {
+            self.spawn()?;
+        }
+        while self.running.len() > 0 {
+            self.write_oldest()?;
+        }
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> Write for Parallel<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = (CHUNK_SIZE - self.chunk.len()).min(buf.len());
+        self.chunk.extend(&buf[..size]);
+        if self.chunk.len() >= CHUNK_SIZE {
+            self.spawn()?;
+        }
+        Ok(size)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Encoder<W> {
+    /// Finishes compressed stream and returns the underlying writer
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Gzip(enc) => enc.finish().into_result(),
+            Encoder::Xz(enc) => enc.finish(),
+            Encoder::Zstd(enc) => enc.finish(),
+            Encoder::Parallel(enc) => enc.finish(),
+            Encoder::Plain(out) => Ok(out),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Encoder::Gzip(ref mut enc) => enc.write(buf),
+            Encoder::Xz(ref mut enc) => enc.write(buf),
+            Encoder::Zstd(ref mut enc) => enc.write(buf),
+            Encoder::Parallel(ref mut enc) => enc.write(buf),
+            Encoder::Plain(ref mut out) => out.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Encoder::Gzip(ref mut enc) => enc.flush(),
+            Encoder::Xz(ref mut enc) => enc.flush(),
+            Encoder::Zstd(ref mut enc) => enc.flush(),
+            Encoder::Parallel(ref mut enc) => enc.flush(),
+            Encoder::Plain(ref mut out) => out.flush(),
+        }
+    }
+}
+
+impl<R: BufRead> Decoder<R> {
+    /// Returns decoder reading from `input`
+    ///
+    /// Format is detected by magic bytes the same way as in `decompress`.
+    pub fn new(mut input: R) -> io::Result<Decoder<R>> {
+        let kind = detect(input.fill_buf()?);
+        match kind {
+            Compression::gzip => Ok(Decoder::Gzip(
+                gzip::MultiDecoder::new(input)?)),
+            Compression::xz => Ok(Decoder::Xz(XzDecoder::new(input))),
+            Compression::zstd => Ok(Decoder::Zstd(
+                zstd::Decoder::with_buffer(input)?)),
+            Compression::none => Ok(Decoder::Plain(input)),
+        }
+    }
+    pub fn compression(&self) -> Compression {
+        match *self {
+            Decoder::Gzip(_) => Compression::gzip,
+            Decoder::Xz(_) => Compression::xz,
+            Decoder::Zstd(_) => Compression::zstd,
+            Decoder::Plain(_) => Compression::none,
+        }
+    }
+}
+
+impl<R: BufRead> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Decoder::Gzip(ref mut dec) => dec.read(buf),
+            Decoder::Xz(ref mut dec) => dec.read(buf),
+            Decoder::Zstd(ref mut dec) => dec.read(buf),
+            Decoder::Plain(ref mut input) => input.read(buf),
+        }
+    }
+}
+
+/// Detects compression by magic bytes at the start of `data`
+pub fn detect(data: &[u8]) -> Compression {
+    if data.starts_with(b"\x1f\x8b") {
+        Compression::gzip
+    } else if data.starts_with(b"\xfd7zXZ\x00") {
+        Compression::xz
+    } else if data.starts_with(b"\x28\xb5\x2f\xfd") {
+        Compression::zstd
+    } else {
+        Compression::none
+    }
+}
+
+/// Decompresses data in any of the supported formats
+///
+/// Format is detected by magic bytes, data that isn't recognized as
+/// compressed is returned as is. Concatenated gzip streams (as in apk)
+/// are decompressed as a whole.
+pub fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut result = Vec::new();
+    match detect(data) {
+        Compression::gzip => {
+            gzip::MultiDecoder::new(data)?.read_to_end(&mut result)?;
+        }
+        Compression::xz => {
+            XzDecoder::new(data).read_to_end(&mut result)?;
+        }
+        Compression::zstd => {
+            zstd::Decoder::new(data)?.read_to_end(&mut result)?;
+        }
+        Compression::none => result.extend(data),
+    }
+    Ok(result)
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use config::Compression;
+    use super::{Settings, CHUNK_SIZE, decompress};
+
+    fn compress(kind: Compression, jobs: usize, data: &[u8]) -> Vec<u8> {
+        let settings = Settings { kind: kind, level: None, jobs: jobs };
+        let mut enc = settings.encoder(Vec::new()).unwrap();
+        // Odd-sized writes, so chunks are filled by parts
+        for part in data.chunks(100003) {
+            enc.write_all(part).unwrap();
+        }
+        enc.finish().unwrap()
+    }
+
+    fn data() -> Vec<u8> {
+        (0..CHUNK_SIZE*5/2).map(|x| (x % 251 ^ x / 7919) as u8).collect()
+    }
+
+    #[test]
+    fn parallel() {
+        let data = data();
+        for &kind in &[Compression::gzip, Compression::xz, Compression::zstd]
+        {
+            let two = compress(kind, 2, &data);
+            assert_eq!(decompress(&two).unwrap(), data);
+            assert!(two == compress(kind, 3, &data),
+                    "{:?} depends on number of threads", kind);
+        }
+    }
+
+    #[test]
+    fn parallel_empty() {
+        for &kind in &[Compression::gzip, Compression::xz, Compression::zstd]
+        {
+            let result = compress(kind, 4, b"");
+            assert!(result.len() > 0);
+            assert_eq!(decompress(&result).unwrap(), b"");
+        }
+    }
+
+    #[test]
+    fn gzip_reproducible() {
+        let result = compress(Compression::gzip, 1, b"hello");
+        // Modification time in the header
+        assert_eq!(&result[4..8], b"\0\0\0\0");
+    }
+}
@@ -0,0 +1,187 @@
+use std::io::{stderr, Write};
+
+
+/// Version constraint operator of a dependency
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Less,
+    LessEq,
+    Eq,
+    GreaterEq,
+    Greater,
+}
+
+/// Single package relation parsed from debian syntax
+///
+/// Architecture qualifiers and restrictions are dropped
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relation {
+    pub name: String,
+    pub version: Option<(Op, String)>,
+}
+
+impl Op {
+    /// Operator as used by rpm, pacman and apk (not debian)
+    pub fn symbol(&self) -> &'static str {
+        match *self {
+            Op::Less => "<",
+            Op::LessEq => "<=",
+            Op::Eq => "=",
+            Op::GreaterEq => ">=",
+            Op::Greater => ">",
+        }
+    }
+}
+
+impl Relation {
+    /// Formats relation like `name>=1.0` as pacman and apk do
+    pub fn compact(&self) -> String {
+        match self.version {
+            Some((op, ref ver)) => {
+                format!("{}{}{}", self.name, op.symbol(), ver)
+            }
+            None => self.name.clone(),
+        }
+    }
+}
+
+/// Splits version into epoch, version and release
+///
+/// Everything after the first dash (usually it's `git describe` suffix)
+/// becomes a release, because rpm, pacman and apk don't allow dashes in
+/// versions. Release is `1` if there is no dash.
+pub fn split_version(version: &str) -> (Option<u32>, String, String) {
+    let (epoch, version) = match version.find(':') {
+        Some(idx) => match version[..idx].parse() {
+            Ok(epoch) => (Some(epoch), &version[idx+1..]),
+            Err(_) => (None, version),
+        },
+        None => (None, version),
+    };
+    match version.find('-') {
+        Some(idx) => (epoch, version[..idx].to_string(),
+                      version[idx+1..].replace("-", ".")),
+        None => (epoch, version.to_string(), String::from("1")),
+    }
+}
+
+/// Parses debian relation field, each item is a list of alternatives
+pub fn parse_relations(value: &str) -> Result<Vec<Vec<Relation>>, String> {
+    let mut result = Vec::new();
+    for item in value.split(',') {
+        let item = item.trim();
+        if item.len() == 0 {
+            continue;
+        }
+        let mut alternatives = Vec::new();
+        for alt in item.split('|') {
+            alternatives.push(try!(parse_single(alt.trim())));
+        }
+        result.push(alternatives);
+    }
+    Ok(result)
+}
+
+/// Same as `parse_relations` for formats having no alternatives
+///
+/// Only the first alternative is used and a warning is printed for others
+pub fn parse_simple_relations(value: &str, field: &str, format: &str)
+    -> Result<Vec<Relation>, String>
+{
+    let mut result = Vec::new();
+    for mut alternatives in try!(parse_relations(value)) {
+        if alternatives.len() > 1 {
+            writeln!(&mut stderr(), "Warning: {} doesn't support \
+                alternatives, only {:?} is used from {:?} in {}",
+                format, alternatives[0].name, value, field).ok();
+        }
+        result.push(alternatives.swap_remove(0));
+    }
+    Ok(result)
+}
+
+fn parse_single(dep: &str) -> Result<Relation, String> {
+    let name_end = dep.find(|c: char| c.is_whitespace() || c == '(' ||
+                                      c == '[' || c == '<' || c == ':')
+        .unwrap_or(dep.len());
+    let name = &dep[..name_end];
+    if name.len() == 0 {
+        return Err(format!("invalid dependency {:?}", dep));
+    }
+    let rest = &dep[name_end..];
+    let version = match (rest.find('('), rest.find(')')) {
+        (Some(start), Some(end)) if start < end => {
+            let cond = rest[start+1..end].trim();
+            let ver_start = cond.find(|c: char| {
+                c != '<' && c != '>' && c != '=' && !c.is_whitespace()
+            }).unwrap_or(cond.len());
+            let op = match cond[..ver_start].trim() {
+                "<<" => Op::Less,
+                "<=" | "<" => Op::LessEq,
+                "=" => Op::Eq,
+                ">=" | ">" => Op::GreaterEq,
+                ">>" => Op::Greater,
+                op => return Err(format!(
+                    "invalid version operator {:?} in {:?}", op, dep)),
+            };
+            Some((op, cond[ver_start..].trim().to_string()))
+        }
+        (None, None) => None,
+        _ => return Err(format!("invalid dependency {:?}", dep)),
+    };
+    Ok(Relation {
+        name: name.to_string(),
+        version: version,
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{parse_relations, split_version, Relation, Op};
+
+    fn rel(name: &str, version: Option<(Op, &str)>) -> Relation {
+        Relation {
+            name: name.to_string(),
+            version: version.map(|(op, v)| (op, v.to_string())),
+        }
+    }
+
+    #[test]
+    fn simple() {
+        assert_eq!(parse_relations("libc6, libssl1.1").unwrap(),
+            vec![vec![rel("libc6", None)], vec![rel("libssl1.1", None)]]);
+    }
+
+    #[test]
+    fn versioned() {
+        assert_eq!(parse_relations(
+            "libc6 (>= 2.17), foo (<< 2), bar:any (= 1.0-1) [amd64]").unwrap(),
+            vec![vec![rel("libc6", Some((Op::GreaterEq, "2.17")))],
+                 vec![rel("foo", Some((Op::Less, "2")))],
+                 vec![rel("bar", Some((Op::Eq, "1.0-1")))]]);
+    }
+
+    #[test]
+    fn alternatives() {
+        assert_eq!(parse_relations("mail-transport | exim (>> 4)").unwrap(),
+            vec![vec![rel("mail-transport", None),
+                      rel("exim", Some((Op::Greater, "4")))]]);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(parse_relations("foo (~ 1)").is_err());
+        assert!(parse_relations("foo (>= 1").is_err());
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(split_version("1.2.3"),
+            (None, "1.2.3".to_string(), "1".to_string()));
+        assert_eq!(split_version("1.2.3-34-gde103b3"),
+            (None, "1.2.3".to_string(), "34.gde103b3".to_string()));
+        assert_eq!(split_version("2:1.0"),
+            (Some(2), "1.0".to_string(), "1".to_string()));
+    }
+}
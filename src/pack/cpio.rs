@@ -0,0 +1,93 @@
+use std::io::{self, Read, Write};
+
+
+/// Fields of cpio entry header, names are the same as in `struct stat`
+#[derive(Debug, Clone, Default)]
+pub struct Header {
+    pub ino: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u32,
+    pub mtime: u32,
+    pub size: u32,
+    pub rdev_major: u32,
+    pub rdev_minor: u32,
+}
+
+/// Writer of cpio archives in "new ascii" (newc) format, as used in rpm
+pub struct Builder<W: Write> {
+    out: W,
+    written: u64,
+}
+
+impl<W: Write> Builder<W> {
+    pub fn new(out: W) -> Builder<W> {
+        Builder {
+            out: out,
+            written: 0,
+        }
+    }
+    fn pad(&mut self) -> io::Result<()> {
+        let pad = ((4 - self.written % 4) % 4) as usize;
+        if pad > 0 {
+            try!(self.out.write_all(&b"\0\0\0"[..pad]));
+            self.written += pad as u64;
+        }
+        Ok(())
+    }
+    fn write_header(&mut self, name: &str, head: &Header) -> io::Result<()> {
+        try!(write!(self.out,
+            "070701{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}\
+             {:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{}\0",
+            head.ino, head.mode, head.uid, head.gid, head.nlink, head.mtime,
+            head.size, 0, 0, head.rdev_major, head.rdev_minor,
+            name.len() + 1, 0, name));
+        self.written += 110 + name.len() as u64 + 1;
+        self.pad()
+    }
+    /// Appends an entry, exactly `head.size` bytes are read from `data`
+    pub fn append<R: Read>(&mut self, name: &str, head: &Header, data: R)
+        -> io::Result<()>
+    {
+        try!(self.write_header(name, head));
+        let bytes = try!(io::copy(&mut data.take(head.size as u64),
+                                  &mut self.out));
+        if bytes != head.size as u64 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                format!("file {:?} changed while reading", name)));
+        }
+        self.written += bytes;
+        self.pad()
+    }
+    /// Writes the trailer and returns total uncompressed size of the archive
+    pub fn finish(mut self) -> io::Result<(W, u64)> {
+        try!(self.write_header("TRAILER!!!", &Header {
+            nlink: 1,
+            .. Header::default()
+        }));
+        Ok((self.out, self.written))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{Builder, Header};
+
+    #[test]
+    fn padding() {
+        let mut arch = Builder::new(Vec::new());
+        // 110 + 2 bytes of name, padded to 112 and 5 bytes of data to 8
+        arch.append("a", &Header { size: 5, .. Header::default() },
+                    &b"hello"[..]).unwrap();
+        let (buf, size) = arch.finish().unwrap();
+        assert_eq!(&buf[..6], b"070701");
+        assert_eq!(&buf[110..112], b"a\0");
+        assert_eq!(&buf[112..120], b"hello\0\0\0");
+        // Trailer: 110 + 11 bytes of name, padded to 124
+        assert_eq!(&buf[120..126], b"070701");
+        assert_eq!(&buf[230..], &b"TRAILER!!!\0\0\0\0"[..]);
+        assert_eq!(size, 244);
+    }
+}
@@ -0,0 +1,306 @@
+use std::env;
+use std::io::{self, Write, stderr};
+use std::fs::{create_dir_all, metadata};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::collections::{BTreeMap, HashMap};
+
+use config::{Metadata, Scripts, Conffiles, Priority, MultiArch};
+use super::elf;
+use super::files::{Entry, Kind};
+use super::permissions::Attributes;
+
+
+/// Directory of debug files in the package, as used by gdb and debhelper
+const DEBUG_DIR: &str = "usr/lib/debug/.build-id";
+
+/// Debug files split out of the package
+pub struct Split {
+    /// Entries of the debug symbols package (including directories)
+    pub files: Vec<Entry>,
+    /// Build ids of all binaries which have debug files, sorted
+    pub build_ids: Vec<String>,
+}
+
+
+fn objcopy(args: &[&str], source: &Path, dest: &Path) -> io::Result<()> {
+    // Cross-compiled binaries may need `OBJCOPY=aarch64-linux-gnu-objcopy`
+    let cmd = env::var("OBJCOPY").unwrap_or(String::from("objcopy"));
+    let status = try!(Command::new(&cmd).args(args).arg(source).arg(dest)
+        .status()
+        .map_err(|e| io::Error::new(e.kind(),
+            format!("can't run {}: {}", cmd, e))));
+    if !status.success() {
+        return Err(io::Error::new(io::ErrorKind::Other,
+            format!("{} {} {:?} failed: {}",
+                    cmd, args.join(" "), source, status)));
+    }
+    Ok(())
+}
+
+/// Returns build id of the binary if it has debugging information
+fn debug_build_id(entry: &Entry, path: &Path) -> io::Result<Option<String>> {
+    let header = match try!(elf::read_header(path)) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    if header.kind != elf::ET_EXEC && header.kind != elf::ET_DYN {
+        return Ok(None);
+    }
+    let sections = try!(elf::read_sections(path, &header));
+    if !sections.iter().any(|s| {
+        s.name.starts_with(".debug_") || s.name.starts_with(".zdebug_")
+    }) {
+        return Ok(None);
+    }
+    let id = try!(elf::read_build_id(path, &header, &sections));
+    if id.is_none() {
+        writeln!(&mut stderr(), "Warning: /{} has debugging information \
+            but no build id, it's left unstripped",
+            entry.name.display()).ok();
+    }
+    Ok(id.and_then(|id| if id.len() > 2 { Some(id) } else { None }))
+}
+
+/// Returns path of the debug file relative to `DEBUG_DIR`
+fn debug_name(build_id: &str) -> PathBuf {
+    PathBuf::from(&build_id[..2]).join(format!("{}.debug", &build_id[2..]))
+}
+
+fn new_entry(name: PathBuf, source: Option<PathBuf>, kind: Kind, mode: u32,
+    mtime: u32)
+    -> Entry
+{
+    Entry {
+        name: name,
+        source: source,
+        kind: kind,
+        mode: mode,
+        attrs: Attributes::default(),
+        xattrs: BTreeMap::new(),
+        mtime: mtime,
+    }
+}
+
+/// Moves debugging information of ELF binaries in `files` to separate files
+///
+/// Binaries are replaced by stripped copies written into `tmp` (which must
+/// live until packages are written), debug files are named by build id
+/// and returned as entries of a debug symbols package.
+pub fn split(files: &mut [Entry], tmp: &Path, mtime: u32)
+    -> io::Result<Split>
+{
+    let mut debug_files = BTreeMap::new();
+    let mut sizes = HashMap::new();
+    for (idx, entry) in files.iter_mut().enumerate() {
+        let source = match entry.file_path() {
+            Some(path) => path.to_path_buf(),
+            None => continue,
+        };
+        let id = match try!(debug_build_id(entry, &source)) {
+            Some(id) => id,
+            None => continue,
+        };
+        let name = debug_name(&id);
+        let debug = tmp.join(&name);
+        // Same binary may be installed twice
+        if !debug_files.contains_key(&id) {
+            try!(create_dir_all(tmp.join(&id[..2])));
+            try!(objcopy(&["--only-keep-debug", "--compress-debug-sections"],
+                         &source, &debug));
+            debug_files.insert(id.clone(), name);
+        }
+        let stripped = tmp.join(format!("stripped.{}", idx));
+        try!(objcopy(&["--strip-unneeded", "--remove-section=.comment",
+                       &format!("--add-gnu-debuglink={}", debug.display())],
+                     &source, &stripped));
+        let size = try!(metadata(&stripped)).len();
+        entry.source = Some(stripped);
+        entry.kind = Kind::File { size: size };
+        sizes.insert(entry.name.clone(), size);
+    }
+    // Hard links to the stripped files have a new size too
+    for entry in files.iter_mut() {
+        if let Kind::HardLink { ref target, ref mut size } = entry.kind {
+            if let Some(&new_size) = sizes.get(target) {
+                *size = new_size;
+            }
+        }
+    }
+
+    Ok(Split {
+        files: try!(package_files(&debug_files, tmp, mtime)),
+        build_ids: debug_files.keys().cloned().collect(),
+    })
+}
+
+/// Returns entries of the debug symbols package for files in `tmp`
+///
+/// Files are keyed by build id, parent directories are added too.
+fn package_files(debug_files: &BTreeMap<String, PathBuf>, tmp: &Path,
+    mtime: u32)
+    -> io::Result<Vec<Entry>>
+{
+    let mut result = Vec::new();
+    if debug_files.len() > 0 {
+        let mut dir = PathBuf::new();
+        for component in Path::new(DEBUG_DIR).iter() {
+            dir.push(component);
+            result.push(new_entry(dir.clone(), None, Kind::Dir, 0o755, mtime));
+        }
+    }
+    let mut last_dir = None;
+    for (id, name) in debug_files {
+        if last_dir != Some(&id[..2]) {
+            result.push(new_entry(Path::new(DEBUG_DIR).join(&id[..2]), None,
+                              Kind::Dir, 0o755, mtime));
+            last_dir = Some(&id[..2]);
+        }
+        let source = tmp.join(name);
+        let size = try!(metadata(&source)).len();
+        result.push(new_entry(Path::new(DEBUG_DIR).join(name), Some(source),
+                          Kind::File { size: size }, 0o644, mtime));
+    }
+    Ok(result)
+}
+
+/// Returns metadata of the debug symbols package for the package `meta`
+///
+/// The `meta` must have variables expanded already. Fields are the same
+/// as debhelper sets for automatic `-dbgsym` packages, others are
+/// inherited, except relations and scripts that only make sense for the
+/// original package.
+pub fn package_metadata(meta: &Metadata, version: &str, split: &Split)
+    -> Metadata
+{
+    let mut extra_fields = BTreeMap::new();
+    extra_fields.insert(String::from("Auto-Built-Package"),
+                        String::from("debug-symbols"));
+    extra_fields.insert(String::from("Build-Ids"),
+                        split.build_ids.join(" "));
+    Metadata {
+        name: format!("{}-dbgsym", meta.name),
+        short_description: format!("debug symbols for {}", meta.name),
+        long_description: format!("This package contains debugging \
+            symbols for the binaries of {}.", meta.name),
+        depends: Some(format!("{} (= {})", meta.name, version)),
+        section: Some(String::from("debug")),
+        priority: Some(Priority::optional),
+        pre_depends: None,
+        recommends: None,
+        suggests: None,
+        conflicts: None,
+        breaks: None,
+        replaces: None,
+        provides: None,
+        essential: false,
+        multi_arch: match meta.multi_arch {
+            Some(MultiArch::same) => Some(MultiArch::same),
+            _ => None,
+        },
+        extra_fields: extra_fields,
+        scripts: Scripts {
+            preinst: None,
+            postinst: None,
+            prerm: None,
+            postrm: None,
+        },
+        conffiles: Conffiles {
+            auto: false,
+            add: Vec::new(),
+            exclude: Vec::new(),
+        },
+        .. meta.clone()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::fs::{File, create_dir_all};
+    use tempfile::tempdir;
+
+    use config::{Config, Metadata};
+    use pack::deb::format_deb_control;
+    use pack::template::{expand_metadata, Vars};
+    use super::{Split, debug_name, package_files, package_metadata};
+
+    /// Parses metadata of package `hello` with additional YAML `fields`
+    fn metadata(fields: &str) -> Metadata {
+        Config::parse_string(&format!("minimum-bulk: v0.4.5\n\
+            metadata:\n  \
+              name: hello\n  \
+              short-description: hello world\n  \
+              long-description: prints hello world\n\
+            {}", fields)).unwrap().metadata.unwrap()
+    }
+
+    #[test]
+    fn layout() {
+        let tmp = tempdir().unwrap();
+        let mut debug_files = BTreeMap::new();
+        for id in &["ab0123", "abcdef", "cd4567"] {
+            let name = debug_name(id);
+            create_dir_all(tmp.path().join(&id[..2])).unwrap();
+            File::create(tmp.path().join(&name)).unwrap();
+            debug_files.insert(id.to_string(), name);
+        }
+        let files = package_files(&debug_files, tmp.path(), 1).unwrap();
+        let names = files.iter().map(|e| e.name.display().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, [
+            "usr",
+            "usr/lib",
+            "usr/lib/debug",
+            "usr/lib/debug/.build-id",
+            "usr/lib/debug/.build-id/ab",
+            "usr/lib/debug/.build-id/ab/0123.debug",
+            "usr/lib/debug/.build-id/ab/cdef.debug",
+            "usr/lib/debug/.build-id/cd",
+            "usr/lib/debug/.build-id/cd/4567.debug",
+        ]);
+        assert_eq!(files[5].source.as_ref().unwrap(),
+                   &tmp.path().join("ab/0123.debug"));
+        assert_eq!(files[5].mode, 0o644);
+        assert_eq!(files[4].mode, 0o755);
+    }
+
+    #[test]
+    fn control() {
+        let mut meta = metadata("  \
+            maintainer: Jane Doe <jane@example.com>\n  \
+            homepage: https://example.com/${name}\n  \
+            multi-arch: same\n  \
+            provides: greeter\n  \
+            extra-fields:\n    \
+              X-Built-By: ${name} ci\n");
+        meta.scripts.postinst = Some(String::from("#!/bin/sh\n"));
+        let meta = expand_metadata(&meta,
+            &mut Vars::new(&meta.name, "1.0-1", "amd64")).unwrap();
+        let split = Split {
+            files: Vec::new(),
+            build_ids: vec![String::from("ab0123"), String::from("cd4567")],
+        };
+        let dbg = package_metadata(&meta, "1.0-1", &split);
+        assert!(dbg.scripts.postinst.is_none());
+        let mut buf = Vec::new();
+        format_deb_control(&mut buf, &dbg, "1.0-1", "amd64", 1).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "\
+            Package: hello-dbgsym\n\
+            Version: 1.0-1\n\
+            Architecture: amd64\n\
+            Maintainer: Jane Doe <jane@example.com>\n\
+            Installed-Size: 1\n\
+            Depends: hello (= 1.0-1)\n\
+            Section: debug\n\
+            Priority: optional\n\
+            Multi-Arch: same\n\
+            Homepage: https://example.com/hello\n\
+            Description: debug symbols for hello\n \
+             This package contains debugging symbols for the binaries \
+             of hello.\n\
+            Auto-Built-Package: debug-symbols\n\
+            Build-Ids: ab0123 cd4567\n");
+    }
+}
@@ -1,21 +1,311 @@
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
+use std::fs::File;
+use std::path::{Path, PathBuf};
 
-use config::Metadata;
+use config::{Metadata, Scripts, Conffiles};
 use deb_ext::WriteDebExt;
+use super::files::Entry;
 
 
 pub fn format_deb_control<W: Write>(out: &mut W, meta: &Metadata,
-    version: &str, architecture: &str)
+    version: &str, architecture: &str, installed_size: u64)
     -> io::Result<()>
 {
     try!(out.write_kv("Package", &meta.name));
     try!(out.write_kv("Version", &version));
-    try!(out.write_kv("Maintainer", "bulk"));
     try!(out.write_kv("Architecture", architecture));
-    if let Some(ref deps) = meta.depends {
-        try!(out.write_kv("Depends", deps));
+    if meta.essential {
+        try!(out.write_kv("Essential", "yes"));
+    }
+    try!(out.write_kv("Maintainer",
+        meta.maintainer.as_ref().map(|x| &x[..]).unwrap_or("bulk")));
+    try!(out.write_kv("Installed-Size", &installed_size.to_string()));
+    // Same order as in deb-control(5)
+    let relations = [
+        ("Pre-Depends", &meta.pre_depends),
+        ("Depends", &meta.depends),
+        ("Recommends", &meta.recommends),
+        ("Suggests", &meta.suggests),
+        ("Breaks", &meta.breaks),
+        ("Conflicts", &meta.conflicts),
+        ("Provides", &meta.provides),
+        ("Replaces", &meta.replaces),
+    ];
+    for &(key, value) in relations.iter() {
+        if let Some(ref value) = *value {
+            try!(out.write_kv(key, value));
+        }
+    }
+    if let Some(ref section) = meta.section {
+        try!(out.write_kv("Section", section));
+    }
+    if let Some(priority) = meta.priority {
+        try!(out.write_kv("Priority", &format!("{:?}", priority)));
+    }
+    if let Some(multi_arch) = meta.multi_arch {
+        try!(out.write_kv("Multi-Arch", &format!("{:?}", multi_arch)));
+    }
+    if let Some(ref homepage) = meta.homepage {
+        try!(out.write_kv("Homepage", homepage));
     }
     try!(out.write_kv("Description",
         &format!("{}\n{}", meta.short_description, meta.long_description)));
+    for (key, value) in &meta.extra_fields {
+        try!(out.write_kv(key, value));
+    }
     Ok(())
 }
+
+/// Reads maintainer scripts in the order they are put into control archive
+///
+/// Each script is either inline script text (if it starts with a shebang
+/// or spans multiple lines) or a path to the file
+pub fn read_scripts(scripts: &Scripts)
+    -> io::Result<Vec<(&'static str, Vec<u8>)>>
+{
+    let mut result = Vec::new();
+    let items = [
+        ("preinst", &scripts.preinst),
+        ("postinst", &scripts.postinst),
+        ("prerm", &scripts.prerm),
+        ("postrm", &scripts.postrm),
+    ];
+    for &(name, value) in items.iter() {
+        let value = match *value {
+            Some(ref value) => value,
+            None => continue,
+        };
+        let data = if value.starts_with("#!") || value.contains('\n') {
+            value.as_bytes().to_vec()
+        } else {
+            let mut buf = Vec::new();
+            try!(File::open(value)
+                .and_then(|mut f| f.read_to_end(&mut buf))
+                .map_err(|e| io::Error::new(e.kind(),
+                    format!("can't read {} script {:?}: {}",
+                            name, value, e))));
+            buf
+        };
+        if !data.starts_with(b"#!") {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("{} script must start with a shebang (#!)", name)));
+        }
+        result.push((name, data));
+    }
+    Ok(result)
+}
+
+/// Returns shell command running maintainer script with `args`
+///
+/// Script is fed to the interpreter from its shebang line through a
+/// here-document, so it can be embedded into other package formats.
+pub fn script_call(args: &str, script: &[u8]) -> Vec<u8> {
+    let text = String::from_utf8_lossy(script);
+    let interpreter = text[2..].lines().next().unwrap_or("").trim();
+    let mut out = format!("{} /dev/stdin {} <<'__BULK_SCRIPT_END__'\n",
+                          interpreter, args).into_bytes();
+    out.extend(script);
+    if !script.ends_with(b"\n") {
+        out.push(b'\n');
+    }
+    out.extend(b"__BULK_SCRIPT_END__\n");
+    out
+}
+
+
+/// Returns sorted list of conffiles in the form of absolute paths
+///
+/// All regular files under `etc` are conffiles unless disabled in config.
+pub fn conffiles(files: &[Entry], cfg: &Conffiles)
+    -> io::Result<Vec<PathBuf>>
+{
+    let root = Path::new("/");
+    let mut result = Vec::new();
+    if cfg.auto {
+        for entry in files {
+            if entry.name.starts_with("etc") && entry.is_file() {
+                result.push(root.join(&entry.name));
+            }
+        }
+    }
+    for path in &cfg.add {
+        let rel = path.strip_prefix("/").unwrap_or(path);
+        if !files.iter().any(|x| x.name == rel) {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                format!("conffile {:?} is not in the package", path)));
+        }
+        result.push(root.join(rel));
+    }
+    result.retain(|x| !cfg.exclude.iter().any(|e| {
+        root.join(e.strip_prefix("/").unwrap_or(e)) == *x
+    }));
+    result.sort();
+    result.dedup();
+    Ok(result)
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+    use config::{Config, Metadata, Scripts, Conffiles};
+    use pack::files::{Entry, Kind};
+    use super::{read_scripts, conffiles, format_deb_control};
+
+    /// Parses metadata of package `hello` with additional YAML `fields`
+    fn metadata(fields: &str) -> Metadata {
+        Config::parse_string(&format!("minimum-bulk: v0.4.5\n\
+            metadata:\n  \
+              name: hello\n  \
+              short-description: hello world\n  \
+              long-description: prints hello world\n\
+            {}", fields)).unwrap().metadata.unwrap()
+    }
+
+    #[test]
+    fn control() {
+        let mut meta = metadata("  \
+            essential: true\n  \
+            maintainer: Jane Doe <jane@example.com>\n  \
+            section: utils\n  \
+            priority: optional\n  \
+            homepage: https://example.com\n  \
+            pre-depends: dpkg (>= 1.15)\n  \
+            depends: libc6\n  \
+            recommends: hello-doc\n  \
+            suggests: bash\n  \
+            conflicts: hi\n  \
+            breaks: hello-old (<< 1.0)\n  \
+            replaces: hello-old\n  \
+            provides: greeter\n  \
+            multi-arch: foreign\n  \
+            extra-fields:\n    \
+              X-Built-By: ci\n");
+        meta.long_description = String::from(
+            "prints hello world\n\nin many languages");
+        let mut buf = Vec::new();
+        format_deb_control(&mut buf, &meta, "1.0-1", "amd64", 12).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "\
+            Package: hello\n\
+            Version: 1.0-1\n\
+            Architecture: amd64\n\
+            Essential: yes\n\
+            Maintainer: Jane Doe <jane@example.com>\n\
+            Installed-Size: 12\n\
+            Pre-Depends: dpkg (>= 1.15)\n\
+            Depends: libc6\n\
+            Recommends: hello-doc\n\
+            Suggests: bash\n\
+            Breaks: hello-old (<< 1.0)\n\
+            Conflicts: hi\n\
+            Provides: greeter\n\
+            Replaces: hello-old\n\
+            Section: utils\n\
+            Priority: optional\n\
+            Multi-Arch: foreign\n\
+            Homepage: https://example.com\n\
+            Description: hello world\n \
+             prints hello world\n \
+             .\n \
+             in many languages\n\
+            X-Built-By: ci\n");
+    }
+
+    fn scripts(postinst: Option<&str>, prerm: Option<&str>) -> Scripts {
+        Scripts {
+            preinst: None,
+            postinst: postinst.map(|x| x.to_string()),
+            prerm: prerm.map(|x| x.to_string()),
+            postrm: None,
+        }
+    }
+
+    #[test]
+    fn inline_scripts() {
+        let result = read_scripts(&scripts(
+            Some("#!/bin/sh\nldconfig\n"),
+            Some("#!/usr/bin/env true"))).unwrap();
+        assert_eq!(result, vec![
+            ("postinst", b"#!/bin/sh\nldconfig\n".to_vec()),
+            ("prerm", b"#!/usr/bin/env true".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn script_files() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("postinst");
+        File::create(&path).unwrap().write_all(b"#!/bin/sh\n").unwrap();
+        let result = read_scripts(&scripts(
+            Some(path.to_str().unwrap()), None)).unwrap();
+        assert_eq!(result, vec![("postinst", b"#!/bin/sh\n".to_vec())]);
+
+        let err = read_scripts(&scripts(
+            Some(dir.path().join("missing").to_str().unwrap()), None))
+            .unwrap_err();
+        assert!(err.to_string().starts_with("can't read postinst script"));
+    }
+
+    #[test]
+    fn no_shebang() {
+        let err = read_scripts(&scripts(None, Some("set -e\nexit 0\n")))
+            .unwrap_err();
+        assert_eq!(err.to_string(),
+                   "prerm script must start with a shebang (#!)");
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("prerm");
+        File::create(&path).unwrap().write_all(b"exit 0\n").unwrap();
+        assert!(read_scripts(&scripts(None, Some(path.to_str().unwrap())))
+                .is_err());
+    }
+
+    fn files() -> Vec<Entry> {
+        vec![
+            Entry::synthetic("etc", Kind::Dir),
+            Entry::synthetic("etc/app", Kind::Dir),
+            Entry::synthetic("etc/app/main.conf", Kind::File { size: 1 }),
+            Entry::synthetic("etc/app/defaults.conf", Kind::File { size: 1 }),
+            Entry::synthetic("etc/app/link",
+                             Kind::Symlink(PathBuf::from("main.conf"))),
+            Entry::synthetic("var/lib/app/state", Kind::File { size: 1 }),
+        ]
+    }
+
+    fn cfg(auto: bool, add: &[&str], exclude: &[&str]) -> Conffiles {
+        Conffiles {
+            auto: auto,
+            add: add.iter().map(PathBuf::from).collect(),
+            exclude: exclude.iter().map(PathBuf::from).collect(),
+        }
+    }
+
+    #[test]
+    fn auto_conffiles() {
+        assert_eq!(conffiles(&files(), &cfg(true, &[], &[])).unwrap(), vec![
+            PathBuf::from("/etc/app/defaults.conf"),
+            PathBuf::from("/etc/app/main.conf"),
+        ]);
+        assert_eq!(conffiles(&files(), &cfg(false, &[], &[])).unwrap(),
+                   Vec::<PathBuf>::new());
+    }
+
+    #[test]
+    fn add_and_exclude() {
+        // Relative and duplicate paths are normalized
+        let result = conffiles(&files(), &cfg(true,
+            &["/var/lib/app/state", "etc/app/main.conf"],
+            &["/etc/app/defaults.conf"])).unwrap();
+        assert_eq!(result, vec![
+            PathBuf::from("/etc/app/main.conf"),
+            PathBuf::from("/var/lib/app/state"),
+        ]);
+        let err = conffiles(&files(), &cfg(true, &["/etc/app/missing"], &[]))
+            .unwrap_err();
+        assert_eq!(err.to_string(),
+                   "conffile \"/etc/app/missing\" is not in the package");
+    }
+}
@@ -0,0 +1,405 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use std::fs::File;
+use std::path::Path;
+
+
+const EI_CLASS: usize = 4;
+const EI_DATA: usize = 5;
+
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ELFDATA2MSB: u8 = 2;
+
+const EM_386: u16 = 3;
+const EM_MIPS: u16 = 8;
+const EM_PPC: u16 = 20;
+const EM_PPC64: u16 = 21;
+const EM_S390: u16 = 22;
+const EM_ARM: u16 = 40;
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+const EM_RISCV: u16 = 243;
+
+const EF_ARM_VFP_FLOAT: u32 = 0x400;
+
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+
+const SHT_NOBITS: u32 = 8;
+const NT_GNU_BUILD_ID: u32 = 3;
+const DT_NEEDED: u64 = 1;
+const DT_SONAME: u64 = 14;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Elf32,
+    Elf64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+/// Fields of the ELF file header that we care about
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    pub class: Class,
+    pub endian: Endian,
+    /// Object file type (`ET_EXEC`, `ET_DYN`, ...)
+    pub kind: u16,
+    pub machine: u16,
+    pub flags: u32,
+}
+
+/// Section of ELF file as described in the section header table
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    /// Section type (`sh_type`)
+    pub kind: u32,
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Dynamic linking information of ELF file
+#[derive(Debug, Clone, Default)]
+pub struct Dynamic {
+    pub soname: Option<String>,
+    /// Libraries from `DT_NEEDED` entries
+    pub needed: Vec<String>,
+    /// Symbol versions required from each library (`.gnu.version_r`)
+    pub versions: Vec<(String, Vec<String>)>,
+}
+
+impl Endian {
+    pub fn u16(&self, buf: &[u8]) -> u16 {
+        match *self {
+            Endian::Little => (buf[0] as u16) | (buf[1] as u16) << 8,
+            Endian::Big => (buf[1] as u16) | (buf[0] as u16) << 8,
+        }
+    }
+    pub fn u32(&self, buf: &[u8]) -> u32 {
+        match *self {
+            Endian::Little => (self.u16(&buf[0..2]) as u32) |
+                              (self.u16(&buf[2..4]) as u32) << 16,
+            Endian::Big => (self.u16(&buf[2..4]) as u32) |
+                           (self.u16(&buf[0..2]) as u32) << 16,
+        }
+    }
+    pub fn u64(&self, buf: &[u8]) -> u64 {
+        match *self {
+            Endian::Little => (self.u32(&buf[0..4]) as u64) |
+                              (self.u32(&buf[4..8]) as u64) << 32,
+            Endian::Big => (self.u32(&buf[4..8]) as u64) |
+                           (self.u32(&buf[0..4]) as u64) << 32,
+        }
+    }
+}
+
+impl Header {
+    /// Parses ELF header from the beginning of the file
+    ///
+    /// Returns `None` if data doesn't look like an ELF file
+    pub fn parse(buf: &[u8]) -> Option<Header> {
+        if buf.len() < 52 || &buf[..4] != b"\x7fELF" {
+            return None;
+        }
+        let class = match buf[EI_CLASS] {
+            ELFCLASS32 => Class::Elf32,
+            ELFCLASS64 => Class::Elf64,
+            _ => return None,
+        };
+        let endian = match buf[EI_DATA] {
+            ELFDATA2LSB => Endian::Little,
+            ELFDATA2MSB => Endian::Big,
+            _ => return None,
+        };
+        let flags_offset = match class {
+            Class::Elf32 => 36,
+            Class::Elf64 => 48,
+        };
+        if buf.len() < flags_offset + 4 {
+            return None;
+        }
+        Some(Header {
+            class: class,
+            endian: endian,
+            kind: endian.u16(&buf[16..18]),
+            machine: endian.u16(&buf[18..20]),
+            flags: endian.u32(&buf[flags_offset..flags_offset+4]),
+        })
+    }
+    /// Returns debian architecture name for the binary
+    pub fn debian_arch(&self) -> Option<&'static str> {
+        use self::Class::*;
+        use self::Endian::*;
+        match (self.machine, self.class, self.endian) {
+            (EM_X86_64, Elf64, _) => Some("amd64"),
+            (EM_X86_64, Elf32, _) => Some("x32"),
+            (EM_386, _, _) => Some("i386"),
+            (EM_AARCH64, _, Little) => Some("arm64"),
+            (EM_ARM, _, Little) if self.flags & EF_ARM_VFP_FLOAT != 0
+                => Some("armhf"),
+            (EM_ARM, _, Little) => Some("armel"),
+            (EM_PPC64, _, Little) => Some("ppc64el"),
+            (EM_PPC64, _, Big) => Some("ppc64"),
+            (EM_PPC, _, Big) => Some("powerpc"),
+            (EM_S390, Elf64, _) => Some("s390x"),
+            (EM_RISCV, Elf64, _) => Some("riscv64"),
+            (EM_MIPS, Elf64, Little) => Some("mips64el"),
+            (EM_MIPS, Elf32, Little) => Some("mipsel"),
+            (EM_MIPS, Elf32, Big) => Some("mips"),
+            _ => None,
+        }
+    }
+}
+
+/// Reads ELF header of the file if it's an ELF file
+pub fn read_header(path: &Path) -> io::Result<Option<Header>> {
+    let mut buf = [0u8; 64];
+    let mut file = try!(File::open(path));
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(x) => len += x,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Header::parse(&buf[..len]))
+}
+
+fn read_at(file: &mut File, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    try!(file.seek(SeekFrom::Start(offset)));
+    try!(file.take(size).read_to_end(&mut buf));
+    if (buf.len() as u64) < size {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+            "ELF file is truncated"));
+    }
+    Ok(buf)
+}
+
+/// Reads section header table of the ELF file
+///
+/// Only section headers and names are read, so it's fast for large files.
+pub fn read_sections(path: &Path, header: &Header)
+    -> io::Result<Vec<Section>>
+{
+    let e = header.endian;
+    let mut file = try!(File::open(path));
+    let head = try!(read_at(&mut file, 0, 64));
+    let (shoff, shentsize, shnum, shstrndx) = match header.class {
+        Class::Elf32 => (e.u32(&head[32..36]) as u64, e.u16(&head[46..48]),
+                         e.u16(&head[48..50]), e.u16(&head[50..52])),
+        Class::Elf64 => (e.u64(&head[40..48]), e.u16(&head[58..60]),
+                         e.u16(&head[60..62]), e.u16(&head[62..64])),
+    };
+    if shoff == 0 || shnum == 0 || shstrndx >= shnum {
+        return Ok(Vec::new());
+    }
+    let table = try!(read_at(&mut file, shoff,
+                             shentsize as u64 * shnum as u64));
+    let mut sections = Vec::with_capacity(shnum as usize);
+    for entry in table.chunks(shentsize as usize) {
+        // Fields are: name index, type, offset and size
+        sections.push(match header.class {
+            Class::Elf32 if entry.len() >= 24 => {
+                (e.u32(&entry[0..4]), e.u32(&entry[4..8]),
+                 e.u32(&entry[16..20]) as u64, e.u32(&entry[20..24]) as u64)
+            }
+            Class::Elf64 if entry.len() >= 40 => {
+                (e.u32(&entry[0..4]), e.u32(&entry[4..8]),
+                 e.u64(&entry[24..32]), e.u64(&entry[32..40]))
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                "invalid ELF section header size")),
+        });
+    }
+    let (_, _, names_offset, names_size) = sections[shstrndx as usize];
+    let names = try!(read_at(&mut file, names_offset, names_size));
+    Ok(sections.into_iter().map(|(name, kind, offset, size)| {
+        Section {
+            name: c_string(&names, name as usize),
+            kind: kind,
+            offset: offset,
+            size: size,
+        }
+    }).collect())
+}
+
+/// Returns zero-terminated string at `offset` of the string table
+fn c_string(table: &[u8], offset: usize) -> String {
+    let data = table.get(offset..).unwrap_or(b"");
+    let end = data.iter().position(|&c| c == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+/// Reads contents of the section with the specified name if it exists
+fn read_section(path: &Path, sections: &[Section], name: &str)
+    -> io::Result<Option<Vec<u8>>>
+{
+    // Debug files have no data for most sections
+    match sections.iter().find(|s| s.name == name && s.kind != SHT_NOBITS) {
+        Some(section) => {
+            let mut file = try!(File::open(path));
+            read_at(&mut file, section.offset, section.size).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Reads needed libraries and required symbol versions of the ELF file
+pub fn read_dynamic(path: &Path, header: &Header, sections: &[Section])
+    -> io::Result<Dynamic>
+{
+    let e = header.endian;
+    let mut result = Dynamic::default();
+    let strings = match try!(read_section(path, sections, ".dynstr")) {
+        Some(data) => data,
+        None => return Ok(result),
+    };
+    if let Some(data) = try!(read_section(path, sections, ".dynamic")) {
+        let size = match header.class {
+            Class::Elf32 => 8,
+            Class::Elf64 => 16,
+        };
+        for item in data.chunks(size).filter(|x| x.len() == size) {
+            let (tag, value) = match header.class {
+                Class::Elf32 => (e.u32(&item[0..4]) as u64,
+                                 e.u32(&item[4..8]) as u64),
+                Class::Elf64 => (e.u64(&item[0..8]), e.u64(&item[8..16])),
+            };
+            match tag {
+                0 => break,
+                DT_NEEDED => {
+                    result.needed.push(c_string(&strings, value as usize));
+                }
+                DT_SONAME => {
+                    result.soname = Some(c_string(&strings, value as usize));
+                }
+                _ => {}
+            }
+        }
+    }
+    if let Some(data) = try!(read_section(path, sections, ".gnu.version_r"))
+    {
+        // Verneed and Vernaux entries are 16 bytes for both classes
+        let field = |pos: usize, size: usize| -> Option<usize> {
+            data.get(pos..pos+size).map(|x| match size {
+                2 => e.u16(x) as usize,
+                _ => e.u32(x) as usize,
+            })
+        };
+        let mut pos = 0;
+        while let (Some(count), Some(file), Some(aux), Some(next)) =
+            (field(pos+2, 2), field(pos+4, 4), field(pos+8, 4),
+             field(pos+12, 4))
+        {
+            let mut names = Vec::new();
+            let mut aux_pos = pos + aux;
+            for _ in 0..count {
+                match (field(aux_pos+8, 4), field(aux_pos+12, 4)) {
+                    (Some(name), Some(aux_next)) => {
+                        names.push(c_string(&strings, name));
+                        aux_pos += aux_next;
+                    }
+                    _ => break,
+                }
+            }
+            result.versions.push((c_string(&strings, file), names));
+            if next == 0 {
+                break;
+            }
+            pos += next;
+        }
+    }
+    Ok(result)
+}
+
+/// Reads GNU build id of the ELF file and returns it as a hex string
+pub fn read_build_id(path: &Path, header: &Header, sections: &[Section])
+    -> io::Result<Option<String>>
+{
+    let e = header.endian;
+    let notes = match try!(read_section(path, sections,
+                                        ".note.gnu.build-id")) {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+    let align = |x: usize| (x + 3) & !3;
+    let mut pos = 0;
+    while pos + 12 <= notes.len() {
+        let name_size = e.u32(&notes[pos..pos+4]) as usize;
+        let desc_size = e.u32(&notes[pos+4..pos+8]) as usize;
+        let kind = e.u32(&notes[pos+8..pos+12]);
+        let name_start = pos + 12;
+        let desc_start = name_start + align(name_size);
+        let desc = match notes.get(desc_start..desc_start + desc_size) {
+            Some(desc) => desc,
+            None => break,
+        };
+        if kind == NT_GNU_BUILD_ID &&
+            &notes[name_start..name_start+name_size] == b"GNU\0"
+        {
+            return Ok(Some(desc.iter()
+                .map(|b| format!("{:02x}", b)).collect()));
+        }
+        pos = desc_start + align(desc_size);
+    }
+    Ok(None)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::Header;
+
+    fn header(class: u8, data: u8, machine: u16, flags: u32) -> Vec<u8> {
+        let mut buf = vec![0u8; 64];
+        buf[..4].copy_from_slice(b"\x7fELF");
+        buf[4] = class;
+        buf[5] = data;
+        let off = if class == 1 { 36 } else { 48 };
+        for i in 0..4 {
+            let shift = if data == 1 { i*8 } else { (3-i)*8 };
+            buf[off+i] = (flags >> shift) as u8;
+        }
+        for i in 0..2 {
+            let shift = if data == 1 { i*8 } else { (1-i)*8 };
+            buf[18+i] = (machine >> shift) as u8;
+        }
+        return buf;
+    }
+
+    fn arch(buf: &[u8]) -> Option<&'static str> {
+        Header::parse(buf).and_then(|h| h.debian_arch())
+    }
+
+    #[test]
+    fn not_elf() {
+        assert!(Header::parse(b"#!/bin/sh\necho hello world\n").is_none());
+        assert!(Header::parse(b"\x7fELF").is_none());
+    }
+
+    #[test]
+    fn common_arches() {
+        assert_eq!(arch(&header(2, 1, 62, 0)), Some("amd64"));
+        assert_eq!(arch(&header(1, 1, 3, 0)), Some("i386"));
+        assert_eq!(arch(&header(2, 1, 183, 0)), Some("arm64"));
+        assert_eq!(arch(&header(1, 1, 40, 0x05000400)), Some("armhf"));
+        assert_eq!(arch(&header(1, 1, 40, 0x05000200)), Some("armel"));
+        assert_eq!(arch(&header(2, 1, 21, 0)), Some("ppc64el"));
+        assert_eq!(arch(&header(2, 2, 22, 0)), Some("s390x"));
+    }
+
+    #[test]
+    fn unknown_machine() {
+        assert_eq!(arch(&header(2, 1, 0x1234, 0)), None);
+    }
+}
@@ -0,0 +1,635 @@
+use std::io::{self, Write, stderr};
+use std::ffi::OsString;
+use std::fs::{symlink_metadata, read_link};
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
+use std::collections::btree_map::Entry as MapEntry;
+use std::cmp::min;
+use std::os::unix::fs::{PermissionsExt, MetadataExt, FileTypeExt};
+
+use glob::{glob, Pattern, MatchOptions};
+use scan_dir;
+use xattr;
+
+use config::{Metadata, Install, parse_mode, check_filter};
+use super::caps;
+use super::permissions::{Rules, Attributes};
+
+
+/// Type of the package entry with type-specific data
+#[derive(Debug, Clone)]
+pub enum Kind {
+    File { size: u64 },
+    /// Hard link to a file that is earlier in the package
+    HardLink { target: PathBuf, size: u64 },
+    Symlink(PathBuf),
+    Dir,
+    Fifo,
+    CharDevice { major: u32, minor: u32 },
+    BlockDevice { major: u32, minor: u32 },
+}
+
+/// File or directory to be put into a package
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Path in the package relative to the root
+    pub name: PathBuf,
+    /// Path of the file on the host, `None` for the parent directories
+    /// of installed files which are added implicitly
+    pub source: Option<PathBuf>,
+    pub kind: Kind,
+    /// Permission bits with overrides applied
+    pub mode: u32,
+    /// Owner and overrides from config
+    pub attrs: Attributes,
+    /// Extended attributes, including capabilities from config
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+    /// Modification time to store in the package
+    pub mtime: u32,
+}
+
+impl Entry {
+    pub fn is_file(&self) -> bool {
+        matches!(self.kind, Kind::File { .. })
+    }
+    pub fn is_dir(&self) -> bool {
+        matches!(self.kind, Kind::Dir)
+    }
+    /// Returns file type bits of the mode as in `struct stat`
+    pub fn type_bits(&self) -> u32 {
+        match self.kind {
+            Kind::File { .. } | Kind::HardLink { .. } => 0o100000,
+            Kind::Symlink(..) => 0o120000,
+            Kind::Dir => 0o040000,
+            Kind::Fifo => 0o010000,
+            Kind::CharDevice { .. } => 0o020000,
+            Kind::BlockDevice { .. } => 0o060000,
+        }
+    }
+    /// Returns path of the regular file on the host
+    pub fn file_path(&self) -> Option<&Path> {
+        match self.kind {
+            Kind::File { .. } => self.source.as_ref().map(|x| x.as_path()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Entry {
+    /// Creates an entry that has no file on the host, for tests
+    pub fn synthetic(name: &str, kind: Kind) -> Entry {
+        Entry {
+            name: PathBuf::from(name),
+            source: None,
+            mode: if let Kind::Dir = kind { 0o755 } else { 0o644 },
+            kind: kind,
+            attrs: Attributes::default(),
+            xattrs: BTreeMap::new(),
+            mtime: 1,
+        }
+    }
+}
+
+/// Lists files in `dir` recursively, relative to the `dir`
+///
+/// The `name` is the path of `dir` in the package.
+fn scan_files(dir: &Path, name: &Path, filter: &Filter)
+    -> Result<Vec<PathBuf>, io::Error>
+{
+    walk(name, filter, |subdir| {
+        scan_dir::ScanDir::all().skip_backup(true)
+            .read(dir.join(subdir), |iter| {
+                iter.map(|(entry, _name)| {
+                    (entry.file_name(),
+                     entry.file_type().map(|typ| typ.is_dir()))
+                }).collect()
+            }).map_err(|e| invalid(e.to_string()))
+    })
+}
+
+/// Walks directory tree, reading directories with `read_dir`
+///
+/// The `read_dir` returns names of entries of a directory (relative to
+/// the root) and whether each of them is a directory. Files excluded by
+/// the filter are skipped and excluded directories are not descended
+/// into, so they don't even need to be readable.
+fn walk<F>(name: &Path, filter: &Filter, mut read_dir: F)
+    -> Result<Vec<PathBuf>, io::Error>
+    where F: FnMut(&Path) -> io::Result<Vec<(OsString, io::Result<bool>)>>
+{
+    let mut files = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(subdir) = dirs.pop() {
+        for (entry, is_dir) in try!(read_dir(&subdir)) {
+            let path = subdir.join(entry);
+            if filter.is_excluded(&name.join(&path)) {
+                continue;
+            }
+            if try!(is_dir) {
+                dirs.push(path.clone());
+            }
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Compiled `include` and `exclude` patterns
+///
+/// Patterns containing a slash are matched against absolute path in the
+/// package, others against file name. Matching a directory means
+/// matching everything inside it.
+pub struct Filter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl Filter {
+    pub fn new(include: &[String], exclude: &[String]) -> io::Result<Filter>
+    {
+        let compile = |patterns: &[String]| {
+            patterns.iter().map(|pat| {
+                try!(check_filter(pat).map_err(invalid));
+                Pattern::new(pat).map_err(|e| invalid(e.to_string()))
+            }).collect::<io::Result<Vec<_>>>()
+        };
+        Ok(Filter {
+            include: try!(compile(include)),
+            exclude: try!(compile(exclude)),
+        })
+    }
+    fn matches(patterns: &[Pattern], name: &Path) -> bool {
+        let options = MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        let path = Path::new("/").join(name);
+        path.ancestors().take_while(|p| *p != Path::new("/")).any(|p| {
+            patterns.iter().any(|pat| {
+                if pat.as_str().contains('/') {
+                    pat.matches_path_with(p, &options)
+                } else {
+                    p.file_name().map_or(false, |n| {
+                        pat.matches_with(&n.to_string_lossy(), &options)
+                    })
+                }
+            })
+        })
+    }
+    /// Returns true if the path should be put into the package
+    ///
+    /// Directories that aren't included themselves are still put into the
+    /// package if they contain included files.
+    pub fn is_included(&self, name: &Path) -> bool {
+        (self.include.len() == 0 || Filter::matches(&self.include, name)) &&
+            !self.is_excluded(name)
+    }
+    fn is_excluded(&self, name: &Path) -> bool {
+        Filter::matches(&self.exclude, name)
+    }
+}
+
+/// Splits `st_rdev` into major and minor numbers the same way glibc does
+fn split_device(rdev: u64) -> (u32, u32) {
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    (major as u32, minor as u32)
+}
+
+/// Reads extended attributes of a file (not following symlinks)
+///
+/// SELinux labels are skipped as they are specific to the build host.
+fn read_xattrs(path: &Path) -> io::Result<BTreeMap<String, Vec<u8>>> {
+    let mut result = BTreeMap::new();
+    for name in try!(xattr::list(path)) {
+        let name = try!(name.into_string().map_err(|name| {
+            invalid(format!("invalid attribute name {:?} of {:?}",
+                            name, path))
+        }));
+        if name == "security.selinux" {
+            continue;
+        }
+        if let Some(value) = try!(xattr::get(path, &name)) {
+            result.insert(name, value);
+        }
+    }
+    Ok(result)
+}
+
+fn invalid<E: AsRef<str>>(text: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, text.as_ref().to_string())
+}
+
+/// Settings of how files are collected
+pub struct Options {
+    pub filter: Filter,
+    /// Whether to read extended attributes of the files
+    pub xattrs: bool,
+    /// Modification time of all the files unless `clamp_mtime` is set
+    pub mtime: u32,
+    /// Keep real modification times of files but not later than this
+    pub clamp_mtime: Option<u32>,
+}
+
+struct Collector<'a> {
+    rules: &'a Rules,
+    options: &'a Options,
+    entries: BTreeMap<PathBuf, Entry>,
+    /// Directories that are not included by filter, they are added only
+    /// if something inside is included
+    pending: BTreeMap<PathBuf, Entry>,
+    /// Device and inode of files having more than one link
+    inodes: HashMap<PathBuf, (u64, u64)>,
+}
+
+impl<'a> Collector<'a> {
+    /// Adds a file from the host, skips files that don't pass the filter
+    ///
+    /// Sockets can't be packaged, they are skipped with a warning.
+    fn add(&mut self, source: PathBuf, name: PathBuf, mode: Option<u32>)
+        -> io::Result<()>
+    {
+        if self.options.filter.is_excluded(&name) {
+            return Ok(());
+        }
+        let stat = try!(symlink_metadata(&source));
+        let typ = stat.file_type();
+        let kind = if typ.is_file() {
+            Kind::File { size: stat.len() }
+        } else if typ.is_symlink() {
+            Kind::Symlink(try!(read_link(&source)))
+        } else if typ.is_dir() {
+            Kind::Dir
+        } else if typ.is_fifo() {
+            Kind::Fifo
+        } else if typ.is_char_device() {
+            let (major, minor) = split_device(stat.rdev());
+            Kind::CharDevice { major: major, minor: minor }
+        } else if typ.is_block_device() {
+            let (major, minor) = split_device(stat.rdev());
+            Kind::BlockDevice { major: major, minor: minor }
+        } else if typ.is_socket() {
+            writeln!(&mut stderr(), "Warning: skipping socket {:?}, \
+                sockets can't be packaged", source).ok();
+            return Ok(());
+        } else {
+            return Err(invalid(format!("unsupported file type of {:?}",
+                                       source)));
+        };
+        let mut attrs = self.rules.attributes(&name);
+        let mut xattrs = BTreeMap::new();
+        if self.options.xattrs {
+            xattrs = try!(read_xattrs(&source));
+        }
+        let mtime = match self.options.clamp_mtime {
+            Some(limit) => {
+                min(stat.mtime().max(0) as u64, limit as u64) as u32
+            }
+            None => self.options.mtime,
+        };
+        if let Kind::File { .. } = kind {
+            attrs.mode = attrs.mode.or(mode);
+            if let Some(ref value) = attrs.capabilities {
+                xattrs.insert(caps::XATTR_NAME.to_string(), value.clone());
+            }
+        }
+        let entry = Entry {
+            mode: attrs.mode(stat.permissions().mode()) & 0o7777,
+            name: name,
+            source: Some(source),
+            kind: kind,
+            attrs: attrs,
+            xattrs: xattrs,
+            mtime: mtime,
+        };
+        if !self.options.filter.is_included(&entry.name) {
+            if entry.is_dir() {
+                self.pending.insert(entry.name.clone(), entry);
+            }
+            return Ok(());
+        }
+        if entry.is_file() && stat.nlink() > 1 {
+            self.inodes.insert(entry.name.clone(), (stat.dev(), stat.ino()));
+        }
+        match self.entries.entry(entry.name.clone()) {
+            MapEntry::Vacant(e) => {
+                e.insert(entry);
+            }
+            MapEntry::Occupied(e) => {
+                if !e.get().is_dir() || !entry.is_dir() {
+                    return Err(invalid(format!(
+                        "{:?} is added to the package twice: from {:?} \
+                         and from {:?}",
+                        entry.name, e.get().source, entry.source)));
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Adds a file or a whole directory
+    fn add_tree(&mut self, source: PathBuf, name: PathBuf, mode: Option<u32>)
+        -> io::Result<()>
+    {
+        if self.options.filter.is_excluded(&name) {
+            return Ok(());
+        }
+        if try!(symlink_metadata(&source)).file_type().is_dir() {
+            let filter = &self.options.filter;
+            for path in try!(scan_files(&source, &name, filter)) {
+                try!(self.add(source.join(&path), name.join(&path), mode));
+            }
+        }
+        self.add(source, name, mode)
+    }
+    fn install(&mut self, item: &Install) -> io::Result<()> {
+        let mode = match item.mode {
+            Some(ref mode) => Some(try!(parse_mode(mode).map_err(invalid))),
+            None => None,
+        };
+        let dest = item.dest.strip_prefix("/").unwrap_or(&item.dest)
+            .to_path_buf();
+        if item.source.contains(|c| c == '*' || c == '?' || c == '[') {
+            let mut found = false;
+            let paths = try!(glob(&item.source)
+                .map_err(|e| invalid(format!("invalid glob {:?}: {}",
+                                             item.source, e))));
+            for path in paths {
+                let path = try!(path.map_err(|e| invalid(e.to_string())));
+                let name = dest.join(path.file_name().unwrap());
+                try!(self.add_tree(path, name, mode));
+                found = true;
+            }
+            if !found {
+                return Err(invalid(format!("no files match {:?}",
+                                           item.source)));
+            }
+        } else {
+            let source = PathBuf::from(&item.source);
+            let name = if item.dest.to_string_lossy().ends_with('/') {
+                match source.file_name() {
+                    Some(fname) => dest.join(fname),
+                    None => return Err(invalid(format!(
+                        "can't determine file name of {:?}", source))),
+                }
+            } else {
+                dest
+            };
+            try!(self.add_tree(source, name, mode).map_err(|e| {
+                io::Error::new(e.kind(),
+                    format!("can't install {:?}: {}", item.source, e))
+            }));
+        }
+        Ok(())
+    }
+    /// Converts files that are hard links to files earlier in the package
+    /// into `Kind::HardLink`
+    fn link_files(&mut self) {
+        let mut seen = HashMap::new();
+        for (name, entry) in self.entries.iter_mut() {
+            let inode = match self.inodes.get(name) {
+                Some(&inode) => inode,
+                None => continue,
+            };
+            let size = match entry.kind {
+                Kind::File { size } => size,
+                _ => continue,
+            };
+            if let Some(target) = seen.get(&inode) {
+                entry.kind = Kind::HardLink {
+                    target: PathBuf::clone(target),
+                    size: size,
+                };
+                continue;
+            }
+            seen.insert(inode, name.clone());
+        }
+    }
+    /// Adds parent directories that aren't in the package yet
+    ///
+    /// Directories skipped by the filter are taken from the host if they
+    /// exist there, others are created with default mode.
+    fn add_parents(&mut self) {
+        let mut parents = Vec::new();
+        for name in self.entries.keys() {
+            for parent in name.ancestors().skip(1) {
+                if parent == Path::new("") {
+                    break;
+                }
+                if !self.entries.contains_key(parent) {
+                    parents.push(parent.to_path_buf());
+                }
+            }
+        }
+        for name in parents {
+            if let Some(entry) = self.pending.remove(&name) {
+                self.entries.insert(name, entry);
+                continue;
+            }
+            let attrs = self.rules.attributes(&name);
+            self.entries.entry(name.clone()).or_insert(Entry {
+                mode: attrs.mode(0o755),
+                name: name,
+                source: None,
+                kind: Kind::Dir,
+                attrs: attrs,
+                xattrs: BTreeMap::new(),
+                mtime: self.options.mtime,
+            });
+        }
+    }
+}
+
+/// Collects all files of the package sorted by name
+///
+/// Files come from the package directory (if it exists or there is no
+/// `install` section) and from the `install` section of the metadata.
+pub fn collect(dir: &Path, meta: &Metadata, options: &Options)
+    -> io::Result<Vec<Entry>>
+{
+    let rules = try!(Rules::new(&meta.permissions));
+    let mut collector = Collector {
+        rules: &rules,
+        options: options,
+        entries: BTreeMap::new(),
+        pending: BTreeMap::new(),
+        inodes: HashMap::new(),
+    };
+    if meta.install.len() == 0 || dir.exists() {
+        for path in try!(scan_files(dir, Path::new(""), &options.filter)) {
+            try!(collector.add(dir.join(&path), path, None));
+        }
+    }
+    for item in &meta.install {
+        try!(collector.install(item));
+    }
+    collector.link_files();
+    collector.add_parents();
+    Ok(collector.entries.into_iter().map(|(_, entry)| entry).collect())
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsString;
+    use std::fs::{File, create_dir_all};
+    use std::path::{Path, PathBuf};
+    use tempfile::tempdir;
+
+    use config::{Config, Install, Metadata};
+    use super::{Filter, Options, Entry, collect, walk, split_device};
+
+    fn filter(include: &[&str], exclude: &[&str]) -> Filter {
+        Filter::new(
+            &include.iter().map(|x| x.to_string()).collect::<Vec<_>>(),
+            &exclude.iter().map(|x| x.to_string()).collect::<Vec<_>>(),
+        ).unwrap()
+    }
+
+    #[test]
+    fn exclude() {
+        let f = filter(&[], &[".git", "*.swp", "/usr/share/doc"]);
+        assert!(f.is_included(Path::new("usr/bin/x")));
+        assert!(!f.is_included(Path::new(".git")));
+        assert!(!f.is_included(Path::new("usr/lib/x/.git/HEAD")));
+        assert!(!f.is_included(Path::new("usr/bin/.x.swp")));
+        assert!(!f.is_included(Path::new("usr/share/doc/x/README")));
+        assert!(f.is_included(Path::new("usr/share/docs")));
+    }
+
+    #[test]
+    fn include() {
+        let f = filter(&["/usr/bin/*", "/etc/app"], &["*.bak"]);
+        assert!(f.is_included(Path::new("usr/bin/x")));
+        assert!(!f.is_included(Path::new("usr/bin/x.bak")));
+        assert!(!f.is_included(Path::new("usr/bin")));
+        assert!(!f.is_included(Path::new("usr/lib/x")));
+        assert!(f.is_included(Path::new("etc/app/x/y.conf")));
+    }
+
+    #[test]
+    fn devices() {
+        assert_eq!(split_device(0x0103), (1, 3));
+        assert_eq!(split_device(0x0800), (8, 0));
+        // large numbers are split between lower and upper bits
+        assert_eq!(split_device(0x11032c), (259, 300));
+    }
+
+    #[test]
+    fn relative_path() {
+        assert!(Filter::new(&["usr/bin".to_string()], &[]).is_err());
+    }
+
+    fn options(exclude: &[&str]) -> Options {
+        Options {
+            filter: filter(&[], exclude),
+            xattrs: false,
+            mtime: 1,
+            clamp_mtime: None,
+        }
+    }
+
+    fn names(entries: &[Entry]) -> Vec<String> {
+        entries.iter().map(|e| e.name.display().to_string()).collect()
+    }
+
+    #[test]
+    fn excluded_dir_is_not_scanned() {
+        let mut read = Vec::new();
+        let files = walk(Path::new(""), &filter(&[], &["/var/secret"]),
+            |dir| {
+                read.push(dir.to_path_buf());
+                let entries: &[(&str, bool)] = match dir.to_str().unwrap() {
+                    "" => &[("usr", true), ("var", true)],
+                    "var" => &[("log", true), ("secret", true)],
+                    "var/log" => &[("x.log", false)],
+                    _ => &[],
+                };
+                Ok(entries.iter()
+                   .map(|&(name, is_dir)| (OsString::from(name), Ok(is_dir)))
+                   .collect())
+            }).unwrap();
+        let strings = |paths: &[PathBuf]| paths.iter()
+            .map(|p| p.to_str().unwrap().to_string()).collect::<Vec<_>>();
+        assert_eq!(strings(&files),
+                   ["usr", "var", "var/log", "var/log/x.log"]);
+        read.sort();
+        assert_eq!(strings(&read), ["", "usr", "var", "var/log"]);
+    }
+
+    fn metadata() -> Metadata {
+        Config::parse_string("minimum-bulk: v0.4.5\n\
+            metadata:\n  \
+              name: hello\n  \
+              short-description: hello world\n  \
+              long-description: prints hello world\n")
+            .unwrap().metadata.unwrap()
+    }
+
+    fn install(items: &[(&Path, &str)]) -> Metadata {
+        let mut meta = metadata();
+        meta.install = items.iter().map(|&(source, dest)| Install {
+            source: source.to_str().unwrap().to_string(),
+            dest: PathBuf::from(dest),
+            mode: None,
+        }).collect();
+        meta
+    }
+
+    #[test]
+    fn install_glob() {
+        let tmp = tempdir().unwrap();
+        let build = tmp.path().join("build");
+        create_dir_all(build.join("plugins.so")).unwrap();
+        File::create(build.join("plugins.so/x")).unwrap();
+        File::create(build.join("libfoo.so")).unwrap();
+        File::create(build.join("libfoo.a")).unwrap();
+        let meta = install(&[(&build.join("*.so"), "/usr/lib")]);
+        let files = collect(&tmp.path().join("pkg"), &meta, &options(&[]))
+            .unwrap();
+        assert_eq!(names(&files), ["usr", "usr/lib", "usr/lib/libfoo.so",
+            "usr/lib/plugins.so", "usr/lib/plugins.so/x"]);
+
+        let meta = install(&[(&build.join("*.dll"), "/usr/lib")]);
+        let err = collect(&tmp.path().join("pkg"), &meta, &options(&[]))
+            .unwrap_err();
+        assert!(err.to_string().starts_with("no files match"));
+    }
+
+    #[test]
+    fn install_dest() {
+        let tmp = tempdir().unwrap();
+        let tool = tmp.path().join("tool");
+        File::create(&tool).unwrap();
+        // Trailing slash means a directory to put the file into
+        let meta = install(&[(&tool, "/usr/bin/"),
+                             (&tool, "/usr/sbin/other-tool")]);
+        let files = collect(&tmp.path().join("pkg"), &meta, &options(&[]))
+            .unwrap();
+        assert_eq!(names(&files), ["usr", "usr/bin", "usr/bin/tool",
+                                   "usr/sbin", "usr/sbin/other-tool"]);
+    }
+
+    #[test]
+    fn install_with_package_dir() {
+        let tmp = tempdir().unwrap();
+        let tool = tmp.path().join("tool");
+        File::create(&tool).unwrap();
+        let meta = install(&[(&tool, "/usr/bin/")]);
+        // Package directory is optional when `install` is used
+        let pkg = tmp.path().join("pkg");
+        let files = collect(&pkg, &meta, &options(&[])).unwrap();
+        assert_eq!(names(&files), ["usr", "usr/bin", "usr/bin/tool"]);
+        // But it's used if exists
+        create_dir_all(pkg.join("etc")).unwrap();
+        File::create(pkg.join("etc/tool.conf")).unwrap();
+        let files = collect(&pkg, &meta, &options(&[])).unwrap();
+        assert_eq!(names(&files), ["etc", "etc/tool.conf",
+                                   "usr", "usr/bin", "usr/bin/tool"]);
+        // And it's required without `install`
+        let missing = tmp.path().join("missing");
+        assert!(collect(&missing, &metadata(), &options(&[])).is_err());
+    }
+}
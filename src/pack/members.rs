@@ -0,0 +1,297 @@
+use std::io::{self, Read};
+use std::str::from_utf8;
+
+use md5;
+use tar;
+
+use super::compress::decompress;
+
+
+/// A member of a package (an archive member, a file in a tarball or a tag
+/// of rpm header) along with its metadata and a checksum of its contents
+#[derive(Debug, PartialEq)]
+pub struct Member {
+    pub name: String,
+    pub fields: Vec<(&'static str, String)>,
+}
+
+
+fn invalid(text: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, text)
+}
+
+fn slice(data: &[u8], start: usize, len: usize) -> io::Result<&[u8]> {
+    data.get(start..start.saturating_add(len))
+        .ok_or_else(|| invalid("package is truncated"))
+}
+
+fn be32(data: &[u8], offset: usize) -> io::Result<u32> {
+    let bytes = slice(data, offset, 4)?;
+    Ok(bytes.iter().fold(0, |acc, &b| (acc << 8) | b as u32))
+}
+
+fn list_ar(data: &[u8], out: &mut Vec<Member>) -> io::Result<()> {
+    let mut pos = 8;
+    while pos < data.len() {
+        let head = slice(data, pos, 60)?;
+        let field = |start: usize, end: usize| {
+            from_utf8(&head[start..end]).map(|x| x.trim())
+                .map_err(|_| invalid("invalid ar header"))
+        };
+        let name = field(0, 16)?.trim_matches('/').to_string();
+        let size: usize = field(48, 58)?.parse()
+            .map_err(|_| invalid("invalid size in ar header"))?;
+        let body = slice(data, pos + 60, size)?;
+        out.push(Member {
+            name: name.clone(),
+            fields: vec![
+                ("mtime", field(16, 28)?.to_string()),
+                ("owner", format!("{}/{}", field(28, 34)?, field(34, 40)?)),
+                ("mode", field(40, 48)?.to_string()),
+                ("size", size.to_string()),
+                ("md5", format!("{:x}", md5::compute(body))),
+            ],
+        });
+        if name.contains(".tar") {
+            list_tar(&decompress(body)?, &format!("{}:", name), out)?;
+        }
+        pos += 60 + size + size % 2;
+    }
+    Ok(())
+}
+
+fn list_tar(data: &[u8], prefix: &str, out: &mut Vec<Member>)
+    -> io::Result<()>
+{
+    // Numeric fields may be empty (we write control files this way)
+    fn num(value: io::Result<u64>) -> u64 {
+        value.unwrap_or(0)
+    }
+    let mut archive = tar::Archive::new(data);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let mut xattrs = Vec::new();
+        if let Some(exts) = entry.pax_extensions()? {
+            for ext in exts {
+                let ext = ext?;
+                xattrs.push(format!("{}={:x}",
+                    String::from_utf8_lossy(ext.key_bytes()),
+                    md5::compute(ext.value_bytes())));
+            }
+        }
+        let name = format!("{}{}", prefix, entry.path()?.display());
+        let link = entry.link_name()?
+            .map(|x| x.display().to_string()).unwrap_or_default();
+        let mut body = Vec::new();
+        entry.read_to_end(&mut body)?;
+        let head = entry.header();
+        out.push(Member {
+            name: name,
+            fields: vec![
+                ("type", format!("{:?}", head.entry_type())),
+                ("mtime", num(head.mtime()).to_string()),
+                ("owner", format!("{}/{}", num(head.uid().map(u64::from)),
+                                           num(head.gid().map(u64::from)))),
+                ("mode", format!("{:o}", num(head.mode().map(u64::from)))),
+                ("size", body.len().to_string()),
+                ("md5", format!("{:x}", md5::compute(&body))),
+                ("link", link),
+                ("pax", xattrs.join(",")),
+            ],
+        });
+    }
+    Ok(())
+}
+
+/// Lists tags of rpm header (or signature) at `pos`, returns its length
+fn list_rpm_header(data: &[u8], pos: usize, section: &str,
+    out: &mut Vec<Member>)
+    -> io::Result<usize>
+{
+    if slice(data, pos, 4)? != b"\x8e\xad\xe8\x01" {
+        return Err(invalid("invalid rpm header magic"));
+    }
+    let count = be32(data, pos + 8)? as usize;
+    let size = be32(data, pos + 12)? as usize;
+    let index = pos + 16;
+    let store = slice(data, index + count*16, size)?;
+    let mut tags = Vec::with_capacity(count);
+    for i in 0..count {
+        tags.push((be32(data, index + i*16)?,
+                   be32(data, index + i*16 + 8)? as usize));
+    }
+    for &(tag, offset) in &tags {
+        // Data of a tag ends where the next one starts (with padding)
+        let end = tags.iter().map(|&(_, off)| off)
+            .filter(|&off| off > offset)
+            .min().unwrap_or(size);
+        let value = store.get(offset..end)
+            .ok_or_else(|| invalid("invalid offset in rpm header"))?;
+        out.push(Member {
+            name: format!("{}:tag {}", section, tag),
+            fields: vec![
+                ("size", value.len().to_string()),
+                ("md5", format!("{:x}", md5::compute(value))),
+            ],
+        });
+    }
+    Ok(16 + count*16 + size)
+}
+
+fn list_cpio(data: &[u8], prefix: &str, out: &mut Vec<Member>)
+    -> io::Result<()>
+{
+    let align = |x: usize| (x + 3) & !3;
+    let mut pos = 0;
+    loop {
+        let head = slice(data, pos, 110)?;
+        if &head[..6] != b"070701" {
+            return Err(invalid("invalid cpio header magic"));
+        }
+        let mut fields = Vec::with_capacity(13);
+        for i in 0..13 {
+            let field = from_utf8(&head[6 + i*8..14 + i*8]).ok()
+                .and_then(|x| u32::from_str_radix(x, 16).ok())
+                .ok_or_else(|| invalid("invalid cpio header"))?;
+            fields.push(field as usize);
+        }
+        let name_len = fields[11];
+        let name = slice(data, pos + 110, name_len.saturating_sub(1))?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        pos = align(pos + 110 + name_len);
+        if name == "TRAILER!!!" {
+            return Ok(());
+        }
+        let body = slice(data, pos, fields[6])?;
+        pos = align(pos + fields[6]);
+        out.push(Member {
+            name: format!("{}{}", prefix, name),
+            fields: vec![
+                ("mode", format!("{:o}", fields[1])),
+                ("owner", format!("{}/{}", fields[2], fields[3])),
+                ("nlink", fields[4].to_string()),
+                ("mtime", fields[5].to_string()),
+                ("rdev", format!("{},{}", fields[9], fields[10])),
+                ("size", body.len().to_string()),
+                ("md5", format!("{:x}", md5::compute(body))),
+            ],
+        });
+    }
+}
+
+fn list_rpm(data: &[u8], out: &mut Vec<Member>) -> io::Result<()> {
+    let lead = slice(data, 0, 96)?;
+    out.push(Member {
+        name: String::from("lead"),
+        fields: vec![("md5", format!("{:x}", md5::compute(lead)))],
+    });
+    let mut pos = 96;
+    pos += list_rpm_header(data, pos, "signature", out)?;
+    // Signature is padded to 8 bytes, main header isn't
+    pos = (pos + 7) & !7;
+    pos += list_rpm_header(data, pos, "header", out)?;
+    let payload = slice(data, pos, data.len() - pos)?;
+    list_cpio(&decompress(payload)?, "payload:", out)
+}
+
+/// Lists members of a package of any format that `bulk pack` writes
+///
+/// Debian packages are listed by members of `ar` archive followed by
+/// files in each tarball, rpm by tags of signature and header followed by
+/// files in the payload. Other formats are (compressed) tarballs.
+pub fn list(data: &[u8]) -> io::Result<Vec<Member>> {
+    let mut result = Vec::new();
+    if data.starts_with(b"!<arch>\n") {
+        list_ar(data, &mut result)?;
+    } else if data.starts_with(b"\xed\xab\xee\xdb") {
+        list_rpm(data, &mut result)?;
+    } else {
+        list_tar(&decompress(data)?, "", &mut result)?;
+    }
+    Ok(result)
+}
+
+/// Compares two packages and returns human-readable differences
+///
+/// Returns an empty list if packages are byte-for-byte identical.
+pub fn diff(old: &[u8], new: &[u8]) -> io::Result<Vec<String>> {
+    if old == new {
+        return Ok(Vec::new());
+    }
+    let old_list = list(old)?;
+    let new_list = list(new)?;
+    let mut result = Vec::new();
+    for member in &old_list {
+        match new_list.iter().find(|m| m.name == member.name) {
+            Some(m) if m.fields != member.fields => {
+                let changes = member.fields.iter().zip(&m.fields)
+                    .filter(|&(a, b)| a != b)
+                    .map(|(&(key, ref a), &(_, ref b))| {
+                        format!("{} {} -> {}", key, a, b)
+                    })
+                    .collect::<Vec<_>>();
+                result.push(format!("~ {}: {}",
+                    member.name, changes.join(", ")));
+            }
+            Some(_) => {}
+            None => result.push(format!("- {}", member.name)),
+        }
+    }
+    for member in &new_list {
+        if !old_list.iter().any(|m| m.name == member.name) {
+            result.push(format!("+ {}", member.name));
+        }
+    }
+    if result.len() == 0 {
+        if old_list != new_list {
+            result.push(String::from("members are in different order"));
+        } else {
+            let pos = old.iter().zip(new).position(|(a, b)| a != b)
+                .unwrap_or(old.len().min(new.len()));
+            result.push(format!("members are the same, \
+                                 first difference is at byte {}", pos));
+        }
+    }
+    Ok(result)
+}
+
+
+#[cfg(test)]
+mod test {
+    use tar;
+    use super::{list, diff};
+
+    fn tarball(mtime: u64) -> Vec<u8> {
+        let mut arch = tar::Builder::new(Vec::new());
+        let mut head = tar::Header::new_gnu();
+        head.set_mtime(mtime);
+        head.set_size(5);
+        head.set_mode(0o644);
+        head.set_cksum();
+        arch.append_data(&mut head, "hello", &b"hello"[..]).unwrap();
+        arch.into_inner().unwrap()
+    }
+
+    #[test]
+    fn list_tar() {
+        let members = list(&tarball(1)).unwrap();
+        assert_eq!(members.len(), 1);
+        assert_eq!(members[0].name, "hello");
+        assert_eq!(members[0].fields[..4].to_vec(), vec![
+            ("type", String::from("Regular")),
+            ("mtime", String::from("1")),
+            ("owner", String::from("0/0")),
+            ("mode", String::from("644")),
+        ]);
+        assert_eq!(members[0].fields[5].1,
+                   "5d41402abc4b2a76b9719d911017c592");
+    }
+
+    #[test]
+    fn diff_mtime() {
+        assert_eq!(diff(&tarball(1), &tarball(1)).unwrap().len(), 0);
+        let lines = diff(&tarball(1), &tarball(2)).unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines, vec!["~ hello: mtime 1 -> 2"]);
+    }
+}
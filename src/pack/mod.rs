@@ -1,139 +1,709 @@
 mod ar;
 mod tar;
 mod deb;
+mod elf;
+pub mod compress;
+pub mod convert;
+mod cpio;
+mod rpm;
+mod pacman;
+mod apk;
+mod tarball;
+mod permissions;
+mod files;
+mod caps;
+mod members;
+mod dbgsym;
+mod shlibs;
+mod template;
 
 use std::io;
-use std::io::{stdout, stderr, Write, BufWriter};
+use std::io::{stdout, stderr, Write, BufWriter, Seek, SeekFrom};
 use std::env;
 use std::fs::{File, create_dir, rename, remove_file};
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::error::Error;
 use std::process::exit;
+use std::str::FromStr;
+use std::collections::HashMap;
 
-use argparse::{ArgumentParser, Parse, ParseOption};
+use argparse::{ArgumentParser, Parse, ParseOption, StoreOption, StoreTrue};
+use argparse::Collect;
 use tar::{Builder as Archive};
-use libflate::gzip;
-use scan_dir;
+use tempfile::{tempfile_in, tempdir_in};
 
 use ver;
+use repo::lint;
 use version::Version;
-use config::{Config, Metadata};
-use self::ar::{ArArchive, SIZE_AUTO};
+use config::{Config, Metadata, Compression, Format, Mtime};
+use config::{check_relations, check_compression_level};
+use self::ar::ArArchive;
 use self::tar::ArchiveExt;
-use self::deb::format_deb_control;
+use self::files::{Entry, Kind};
+use self::deb::{format_deb_control, read_scripts, conffiles};
 
 
-fn write_deb(dest: &Path, dir: &Path, meta: &Metadata, version: &String)
-    -> Result<(), io::Error>
-{
-    let mtime = env::var("SOURCE_DATE_EPOCH").ok()
-        .and_then(|x| x.parse().ok()).unwrap_or(1);
-    let file = BufWriter::new(File::create(&dest)?);
-    let mut ar = try!(ArArchive::new(file));
+impl FromStr for Format {
+    type Err = ();
+    fn from_str(value: &str) -> Result<Format, ()> {
+        match value {
+            "deb" => Ok(Format::deb),
+            "rpm" => Ok(Format::rpm),
+            "arch" | "pacman" => Ok(Format::arch),
+            "apk" => Ok(Format::apk),
+            "tar" => Ok(Format::tar),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for Mtime {
+    type Err = ();
+    fn from_str(value: &str) -> Result<Mtime, ()> {
+        match value {
+            "fixed" => Ok(Mtime::fixed),
+            "clamp" => Ok(Mtime::clamp),
+            _ => Err(()),
+        }
+    }
+}
 
-    try!(ar.add("debian-binary", mtime, 0, 0, 0o100644, 4)
-        .and_then(|mut f| f.write_all(b"2.0\n")));
+/// Command-line options of `bulk pack`
+struct Options {
+    config: PathBuf,
+    dir: PathBuf,
+    destdir: PathBuf,
+    version: Option<Version<String>>,
+    architecture: Option<String>,
+    formats: Vec<Format>,
+    compression: Option<Compression>,
+    compression_level: Option<u32>,
+    tar_prefix: Option<PathBuf>,
+    packages: Vec<String>,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    xattrs: bool,
+    mtime: Option<Mtime>,
+    dbgsym: bool,
+    auto_depends: bool,
+    lint: bool,
+    list: bool,
+    verify_reproducible: bool,
+    output: Option<PathBuf>,
+    jobs: usize,
+}
 
-    {
-        let control = try!(ar.add("control.tar.gz",
-            mtime, 0, 0, 0o100644, SIZE_AUTO));
-        let mut creal = gzip::Encoder::new(control)?;
-        {
-            let mut arch = Archive::new(&mut creal);
-            let mut buf = Vec::with_capacity(1024);
-            format_deb_control(&mut buf, &meta, version, "amd64")?;
-            arch.append_blob("control", mtime, &buf)?;
-            arch.finish()?;
+fn source_date_epoch() -> Option<u32> {
+    env::var("SOURCE_DATE_EPOCH").ok().and_then(|x| x.parse().ok())
+}
+
+/// Modification time for all files in a package
+fn package_mtime() -> u32 {
+    source_date_epoch().unwrap_or(1)
+}
+
+/// Compression of the data archive from command line or config
+///
+/// Level from the config is only used with compression from the config.
+fn compression_settings(cfg: &Config, opt: &Options) -> compress::Settings {
+    let kind = opt.compression.unwrap_or(cfg.pack.compression);
+    let level = if kind == cfg.pack.compression {
+        opt.compression_level.or(cfg.pack.compression_level)
+    } else {
+        opt.compression_level
+    };
+    compress::Settings {
+        kind: kind,
+        level: level,
+        jobs: opt.jobs,
+    }
+}
+
+/// Finds architecture of the package by looking at binaries in it
+///
+/// Returns `all` if there are no binaries at all
+fn detect_architecture(files: &[Entry]) -> Result<String, Box<Error>> {
+    let mut found: Option<(&'static str, &Path)> = None;
+    for entry in files {
+        let fullpath = match entry.file_path() {
+            Some(path) => path,
+            None => continue,
+        };
+        let fpath = &entry.name;
+        let header = match try!(elf::read_header(fullpath)) {
+            Some(header) => header,
+            None => continue,
+        };
+        let arch = try!(header.debian_arch().ok_or_else(|| format!(
+            "Unsupported machine type {} of {:?}",
+            header.machine, fpath)));
+        match found {
+            Some((prev, ref prev_path)) if prev != arch => {
+                return Err(format!("Binaries of different architectures \
+                    in a package: {:?} is {}, {:?} is {}",
+                    prev_path, prev, fpath, arch).into());
+            }
+            Some(_) => {}
+            None => found = Some((arch, fpath)),
         }
-        creal.finish().into_result()?;
     }
+    Ok(found.map(|(arch, _)| arch).unwrap_or("all").to_string())
+}
+
+fn write_deb<W: Write>(out: W, tmpdir: &Path, files: &[Entry],
+    meta: &Metadata, version: &String, architecture: &str,
+    compression: compress::Settings, mtime: u32)
+    -> Result<(), io::Error>
+{
+    let scripts = try!(read_scripts(&meta.scripts));
+    let conffiles = try!(conffiles(files, &meta.conffiles));
+    // Data archive is written first into a temporary file, so that
+    // checksums and installed size are known when writing control archive
+    let mut data_file = try!(tempfile_in(tmpdir));
+    let mut md5sums = Vec::new();
+    let mut digests = HashMap::new();
+    let mut installed_size = 0;
     {
-        let data = try!(ar.add("data.tar.gz",
-            mtime, 0, 0, 0o100644, SIZE_AUTO));
-        let mut dreal = gzip::Encoder::new(data)?;
-        let mut files = try!(scan_dir::ScanDir::all().skip_backup(true)
-            .walk(dir, |iter| {
-                iter.map(|(entry, _name)| {
-                    entry.path().strip_prefix(dir).unwrap().to_path_buf()})
-                    .collect::<Vec<_>>()
-            }).map_err(|errs| io::Error::new(io::ErrorKind::InvalidData,
-                errs.iter().map(ToString::to_string).collect::<Vec<_>>()[..]
-                    .join("\n"))));
-        files.sort();
+        let mut dreal = compression.encoder(&mut data_file)?;
         {
             let mut arch = Archive::new(&mut dreal);
-            for fpath in files {
-                arch.append_file_at(dir, fpath, mtime)?;
+            for entry in files {
+                let is_conffile = conffiles.iter().any(|c| {
+                    c.strip_prefix("/").ok() == Some(&entry.name)
+                });
+                match arch.append_entry(entry)? {
+                    Some(sum) => {
+                        // Same as dpkg-gencontrol: size in KiB per file
+                        installed_size += (sum.size + 1023) / 1024;
+                        digests.insert(&entry.name, sum.md5);
+                        if !is_conffile {
+                            md5sums.push((&entry.name, sum.md5));
+                        }
+                    }
+                    None => match entry.kind {
+                        // Hard links take no space, but have a checksum
+                        Kind::HardLink { ref target, .. } => {
+                            if !is_conffile {
+                                md5sums.push((&entry.name, digests[target]));
+                            }
+                        }
+                        _ => installed_size += 1,
+                    },
+                }
             }
             arch.finish()?;
         }
-        dreal.finish().into_result()?;
+        dreal.finish()?;
     }
+    let data_size = try!(data_file.seek(SeekFrom::Current(0)));
+    try!(data_file.seek(SeekFrom::Start(0)));
+
+    let mut ar = try!(ArArchive::new(BufWriter::new(out)));
+
+    try!(ar.add("debian-binary", mtime, 0, 0, 0o100644, 4, &b"2.0\n"[..]));
+
+    // Control archive is small, so it's kept in memory to know its size
+    let control = {
+        let mut creal = compress::gzip_encoder(Vec::new())?;
+        {
+            let mut arch = Archive::new(&mut creal);
+            let mut buf = Vec::with_capacity(1024);
+            format_deb_control(&mut buf, &meta, version, architecture,
+                               installed_size)?;
+            arch.append_blob("control", mtime, 0o644, &buf)?;
+            if conffiles.len() > 0 {
+                let mut buf = Vec::with_capacity(1024);
+                for path in &conffiles {
+                    writeln!(&mut buf, "{}", path.display())?;
+                }
+                arch.append_blob("conffiles", mtime, 0o644, &buf)?;
+            }
+            if md5sums.len() > 0 {
+                let mut buf = Vec::with_capacity(md5sums.len()*64);
+                for &(ref path, ref sum) in &md5sums {
+                    writeln!(&mut buf, "{:x}  {}", sum, path.display())?;
+                }
+                arch.append_blob("md5sums", mtime, 0o644, &buf)?;
+            }
+            for &(name, ref data) in &scripts {
+                arch.append_blob(name, mtime, 0o755, data)?;
+            }
+            arch.finish()?;
+        }
+        creal.finish().into_result()?
+    };
+    try!(ar.add("control.tar.gz", mtime, 0, 0, 0o100644,
+                control.len() as u64, &control[..]));
+    let name = format!("data.tar{}", compression.extension());
+    try!(ar.add(&name, mtime, 0, 0, 0o100644, data_size, &mut data_file));
+    try!(ar.into_inner().flush());
     Ok(())
 }
 
-fn _pack(config: &Path, dir: &Path, destdir: &Path,
-    version: Option<Version<String>>)
+/// Prints files that would be put into the package
+fn print_files(files: &[Entry]) {
+    for entry in files {
+        let owner = match entry.attrs.user {
+            Some(ref user) => user.clone(),
+            None => entry.attrs.uid.to_string(),
+        };
+        let group = match entry.attrs.group {
+            Some(ref group) => group.clone(),
+            None => entry.attrs.gid.to_string(),
+        };
+        // Type letter and size column are the same as in `tar -tv`
+        let (typ, size, suffix) = match entry.kind {
+            Kind::File { size } => ('-', size.to_string(), String::new()),
+            Kind::HardLink { ref target, size } => {
+                ('h', size.to_string(),
+                 format!(" link to /{}", target.display()))
+            }
+            Kind::Symlink(ref link) => {
+                ('l', String::new(), format!(" -> {}", link.display()))
+            }
+            Kind::Dir => ('d', String::new(), String::new()),
+            Kind::Fifo => ('p', String::new(), String::new()),
+            Kind::CharDevice { major, minor } => {
+                ('c', format!("{},{}", major, minor), String::new())
+            }
+            Kind::BlockDevice { major, minor } => {
+                ('b', format!("{},{}", major, minor), String::new())
+            }
+        };
+        println!("{} {:04o} {:<12} {:>10} /{}{}", typ, entry.mode,
+            format!("{}/{}", owner, group), size, entry.name.display(),
+            suffix);
+    }
+}
+
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    try!(File::open(path)?.read_to_end(&mut data));
+    Ok(data)
+}
+
+/// Writes package via temporary file, so no partial package is left
+///
+/// If `verify` is set, package is written twice and it's an error if the
+/// two copies differ. If `dest` is `-` package is written to stdout.
+fn write_package<F>(dest: &Path, kind: &str, verify: bool, f: F)
     -> Result<(), Box<Error>>
+    where F: Fn(&mut Write) -> Result<(), io::Error>
 {
-    let cfg = try!(Config::parse_file(config));
-
-    let version = if let Some(ver) = version {
-        ver.num().to_string()
-    } else {
-        try!(ver::get(&cfg, Path::new("."))).0
+    if dest == Path::new("-") {
+        let out = stdout();
+        let mut out = out.lock();
+        try!(f(&mut out).and_then(|()| out.flush())
+             .map_err(|e| format!("Error writing {}: {}", kind, e)));
+        return Ok(());
+    }
+    let write = |path: &Path| {
+        File::create(path).and_then(|mut file| f(&mut file))
     };
-
-    let ref meta = try!(cfg.metadata
-        .ok_or(format!("No package metadata is in the config")));
-    // TODO(tailhook) not only debian
-    let dest = destdir.join(format!("{}-{}_{}.deb",
-        meta.name, version, "amd64"));
-    if !destdir.exists() {
-        try!(create_dir(&destdir)
-            .map_err(|e| format!("Can't create destination dir: {}", e)));
+    let mut tmpname = dest.as_os_str().to_owned();
+    tmpname.push(".tmp");
+    let tmpname = PathBuf::from(tmpname);
+    try!(write(&tmpname)
+         .map_err(|e| format!("Error writing {}: {}", kind, e)));
+    if verify {
+        let mut second = dest.as_os_str().to_owned();
+        second.push(".verify.tmp");
+        let second = PathBuf::from(second);
+        let result = write(&second)
+            .and_then(|()| members::diff(&read_file(&tmpname)?,
+                                         &read_file(&second)?));
+        remove_file(&second).ok();
+        let differences = try!(result
+            .map_err(|e| format!("Error verifying {}: {}", kind, e)));
+        if differences.len() > 0 {
+            remove_file(&tmpname).ok();
+            return Err(format!("{} {:?} is not reproducible, \
+                differences between two builds:\n  {}",
+                kind, dest.file_name().unwrap_or(dest.as_os_str()),
+                differences.join("\n  ")).into());
+        }
     }
-
-    let tmpname = dest.with_extension(".deb.tmp");
-    try!(write_deb(&tmpname, dir, &meta, &version)
-         .map_err(|e| format!("Error writing deb: {}", e)));
     if dest.exists() {
         try!(remove_file(&dest)
             .map_err(|e| format!("Can't remove old package: {}", e)));
     }
     try!(rename(&tmpname, &dest)
-        .map_err(|e| format!("Can't rename deb to target place: {}", e)));
+        .map_err(|e| format!("Can't rename {} to target place: {}", kind, e)));
     println!("Written {}", dest.display());
     Ok(())
 }
 
+/// Collects files of a single package according to config and options
+fn collect_files(cfg: &Config, meta: &Metadata, opt: &Options)
+    -> Result<Vec<Entry>, Box<Error>>
+{
+    let include = cfg.pack.include.iter().chain(&opt.include).cloned()
+        .collect::<Vec<_>>();
+    let exclude = cfg.pack.exclude.iter().chain(&opt.exclude).cloned()
+        .collect::<Vec<_>>();
+    let options = files::Options {
+        filter: try!(files::Filter::new(&include, &exclude)),
+        xattrs: opt.xattrs || cfg.pack.xattrs,
+        mtime: package_mtime(),
+        clamp_mtime: match opt.mtime.unwrap_or(cfg.pack.mtime) {
+            Mtime::fixed => None,
+            // Without SOURCE_DATE_EPOCH there is nothing to clamp to
+            Mtime::clamp => {
+                Some(source_date_epoch().unwrap_or(u32::max_value()))
+            }
+        },
+    };
+    // Directory in the config is only for this package, so it wins
+    let dir = meta.dir.as_ref().unwrap_or(&opt.dir);
+    let files = try!(files::collect(dir, meta, &options)
+        .map_err(|e| format!("Error collecting files of {}: {}",
+                             meta.name, e)));
+    Ok(files)
+}
+
+/// Returns architecture from options or metadata, detecting `auto` one
+fn package_arch(meta: &Metadata, files: &[Entry], opt: &Options)
+    -> Result<String, Box<Error>>
+{
+    match opt.architecture.as_ref().or(meta.architecture.as_ref()) {
+        Some(arch) if arch == "auto" => detect_architecture(files),
+        Some(arch) => Ok(arch.clone()),
+        None => Ok(String::from("amd64")),
+    }
+}
+
+/// Writes a single package in all requested formats
+///
+/// Variables in metadata must be expanded already. Dependencies in
+/// `auto_depends` are added to debian package only.
+fn write_packages(cfg: &Config, meta: &Metadata, files: &[Entry],
+    version: &String, arch: &str, formats: &[Format],
+    auto_depends: &[String], opt: &Options)
+    -> Result<(), Box<Error>>
+{
+    let mtime = package_mtime();
+    // Package file name is only used if there is no `--output`
+    let destination = |name: String| match opt.output {
+        Some(ref path) => path.clone(),
+        None => opt.destdir.join(name),
+    };
+    // Temporary files are created near the package, so they are likely
+    // on the same filesystem
+    let tmpdir = match opt.output {
+        Some(ref path) if path == Path::new("-") => env::temp_dir(),
+        Some(ref path) => match path.parent() {
+            Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        },
+        None => opt.destdir.clone(),
+    };
+    let tmpdir = &tmpdir;
+    let compression = compression_settings(cfg, opt);
+    let verify = opt.verify_reproducible;
+    for &format in formats {
+        match format {
+            Format::deb => {
+                let mut meta = meta.clone();
+                if auto_depends.len() > 0 {
+                    meta.depends = Some(meta.depends.iter()
+                        .chain(auto_depends)
+                        .map(|x| &x[..]).collect::<Vec<_>>().join(", "));
+                }
+                let dest = destination(format!("{}-{}_{}.deb",
+                    meta.name, version, arch));
+                try!(write_package(&dest, "deb", verify, |out| {
+                    write_deb(out, tmpdir, &files, &meta, &version, arch,
+                              compression, mtime)
+                }));
+                if opt.lint || cfg.pack.lint {
+                    let severities = try!(lint::severities(&cfg.lint));
+                    if !try!(lint::lint_file(&dest, &severities)
+                        .map_err(|e| e.to_string()))
+                    {
+                        return Err(format!("Package {:?} has lint errors",
+                                           dest).into());
+                    }
+                }
+            }
+            Format::rpm => {
+                let dest = destination(
+                    rpm::package_name(&meta, &version, arch));
+                try!(write_package(&dest, "rpm", verify, |out| {
+                    rpm::write_rpm(out, tmpdir, &files, &meta, &version,
+                                   arch, compression, mtime)
+                }));
+            }
+            Format::arch => {
+                // Arch packages are always compressed with zstd
+                let compression = compress::Settings {
+                    kind: Compression::zstd,
+                    level: if compression.kind == Compression::zstd {
+                        compression.level
+                    } else {
+                        None
+                    },
+                    jobs: compression.jobs,
+                };
+                let dest = destination(
+                    pacman::package_name(&meta, &version, arch));
+                try!(write_package(&dest, "arch package", verify, |out| {
+                    pacman::write_pacman(out, tmpdir, &files, &meta,
+                                         &version, arch, compression, mtime)
+                }));
+            }
+            Format::apk => {
+                let dest = destination(apk::package_name(&meta, &version));
+                try!(write_package(&dest, "apk", verify, |out| {
+                    apk::write_apk(out, tmpdir, &files, &meta, &version,
+                                   arch, mtime)
+                }));
+            }
+            Format::tar => {
+                let dest = destination(
+                    tarball::package_name(&meta, &version, compression));
+                let prefix = opt.tar_prefix.as_ref()
+                    .or(cfg.pack.tar_prefix.as_ref());
+                try!(write_package(&dest, "tarball", verify, |out| {
+                    tarball::write_tarball(out, &files,
+                        prefix.map(|x| x.as_path()), compression, mtime)
+                }));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn _pack(opt: Options) -> Result<(), Box<Error>> {
+    let cfg = try!(Config::parse_file(&opt.config));
+
+    let all = cfg.packages();
+    if all.len() == 0 {
+        return Err(format!("No package metadata is in the config").into());
+    }
+    for name in &opt.packages {
+        if !all.iter().any(|m| &m.name == name) {
+            return Err(format!("No package {:?} in the config", name).into());
+        }
+    }
+    let packages = all.iter()
+        .filter(|m| opt.packages.len() == 0 || opt.packages.contains(&m.name))
+        .collect::<Vec<_>>();
+    if opt.list {
+        for meta in &packages {
+            let files = try!(collect_files(&cfg, meta, &opt));
+            if packages.len() > 1 {
+                println!("{}:", meta.name);
+            }
+            print_files(&files);
+        }
+        return Ok(());
+    }
+
+    if opt.jobs == 0 {
+        return Err(format!("`--jobs` must be at least 1").into());
+    }
+    let compression = compression_settings(&cfg, &opt);
+    if let Some(level) = compression.level {
+        try!(check_compression_level(compression.kind, level));
+    }
+    let version = if let Some(ref ver) = opt.version {
+        ver.num().to_string()
+    } else {
+        try!(ver::get(&cfg, Path::new("."))).0
+    };
+    if opt.lint || cfg.pack.lint {
+        try!(lint::severities(&cfg.lint));
+    }
+    let formats = if opt.formats.len() > 0 {
+        opt.formats.clone()
+    } else if cfg.pack.formats.len() > 0 {
+        cfg.pack.formats.clone()
+    } else {
+        vec![Format::deb]
+    };
+    match opt.output {
+        Some(ref output) => {
+            if packages.len() != 1 || formats.len() != 1 ||
+                opt.dbgsym || cfg.pack.dbgsym
+            {
+                return Err(format!("`--output` can only be used to build \
+                    a single package in a single format (and without \
+                    `dbgsym`)").into());
+            }
+            if output == Path::new("-") &&
+                (opt.verify_reproducible || opt.lint || cfg.pack.lint)
+            {
+                return Err(format!("`--verify-reproducible` and `--lint` \
+                    need a package file, so they can't be used when \
+                    writing package to stdout").into());
+            }
+        }
+        None if !opt.destdir.exists() => {
+            try!(create_dir(&opt.destdir)
+                .map_err(|e| format!("Can't create destination dir: {}", e)));
+        }
+        None => {}
+    }
+    for meta in &packages {
+        let mut files = try!(collect_files(&cfg, meta, &opt));
+        let depends = if opt.auto_depends || cfg.pack.auto_depends {
+            try!(shlibs::auto_depends(&files, &cfg.pack.shlibs)
+                .map_err(|e| format!("Error finding dependencies of {}: {}",
+                                     meta.name, e)))
+        } else {
+            Vec::new()
+        };
+        let arch = try!(package_arch(meta, &files, &opt));
+        let meta = &try!(template::expand_metadata(meta,
+            &mut template::Vars::new(&meta.name, &version, &arch)));
+        try!(check_relations(meta));
+        if !opt.dbgsym && !cfg.pack.dbgsym {
+            try!(write_packages(&cfg, meta, &files, &version, &arch,
+                                &formats, &depends, &opt));
+            continue;
+        }
+        // Stripped binaries and debug files are kept here until written
+        let tmp = try!(tempdir_in(&opt.destdir)
+            .map_err(|e| format!("Can't create temporary dir: {}", e)));
+        let split = try!(dbgsym::split(&mut files, tmp.path(),
+                                       package_mtime())
+            .map_err(|e| format!("Error splitting debug info of {}: {}",
+                                 meta.name, e)));
+        try!(write_packages(&cfg, meta, &files, &version, &arch,
+                            &formats, &depends, &opt));
+        if split.files.len() > 0 {
+            // Debug symbols packages are only known to debian tools
+            let dbg_meta = dbgsym::package_metadata(meta, &version, &split);
+            try!(write_packages(&cfg, &dbg_meta, &split.files, &version,
+                                &arch, &[Format::deb], &[], &opt));
+        }
+    }
+    Ok(())
+}
+
 
 pub fn pack(args: Vec<String>) {
-    let mut config = PathBuf::from("bulk.yaml");
-    let mut dir = PathBuf::from("pkg");
-    let mut destdir = PathBuf::from("dist");
-    let mut version = None;
+    let mut opt = Options {
+        config: PathBuf::from("bulk.yaml"),
+        dir: PathBuf::from("pkg"),
+        destdir: PathBuf::from("dist"),
+        version: None,
+        architecture: None,
+        formats: Vec::new(),
+        compression: None,
+        compression_level: None,
+        tar_prefix: None,
+        packages: Vec::new(),
+        include: Vec::new(),
+        exclude: Vec::new(),
+        xattrs: false,
+        mtime: None,
+        dbgsym: false,
+        auto_depends: false,
+        lint: false,
+        list: false,
+        verify_reproducible: false,
+        output: None,
+        jobs: 1,
+    };
     {
         let mut ap = ArgumentParser::new();
-        ap.refer(&mut config)
+        ap.refer(&mut opt.config)
             .add_option(&["-c", "--config"], Parse,
                 "Package configuration file");
-        ap.refer(&mut dir)
+        ap.refer(&mut opt.dir)
             .add_option(&["-d", "--dir"], Parse,
-                "Directory that will be a root of filesystem in a package");
-        ap.refer(&mut destdir)
+                "Directory that will be a root of filesystem in a package. \
+                 Used for packages that have no `dir` in config.");
+        ap.refer(&mut opt.destdir)
             .add_option(&["-D", "--dest-dir"], Parse,
                 "Directory to put package to");
-        ap.refer(&mut version)
+        ap.refer(&mut opt.output)
+            .add_option(&["-o", "--output"], ParseOption,
+                "Write package to this file instead of the destination \
+                 directory, `-` means stdout. Can only be used when a \
+                 single package in a single format is built.");
+        ap.refer(&mut opt.version)
             .add_option(&["--package-version"], ParseOption,
                 "Force package version instead of discovering it.");
+        ap.refer(&mut opt.architecture)
+            .add_option(&["-a", "--architecture"], ParseOption,
+                "Package architecture, overrides the one in config. \
+                 Use `auto` to detect it from binaries in the package.");
+        ap.refer(&mut opt.formats)
+            .add_option(&["--format"], Collect,
+                "Package format: deb (default), rpm, arch, apk or tar. \
+                 May be specified multiple times to build several \
+                 packages at once. Overrides the list in config.");
+        ap.refer(&mut opt.compression)
+            .add_option(&["--compression"], StoreOption,
+                "Compression of the data archive: gzip (default), xz, \
+                 zstd or none. Overrides the one in config.");
+        ap.refer(&mut opt.compression_level)
+            .add_option(&["--compression-level"], StoreOption,
+                "Compression level for xz (0-9) and zstd (1-22)");
+        ap.refer(&mut opt.jobs)
+            .add_option(&["-j", "--jobs"], Parse,
+                "Number of threads to compress data with (default 1). \
+                 With more threads data is split into independently \
+                 compressed chunks, so the package is slightly larger, \
+                 but it's the same for any number of threads above one.");
+        ap.refer(&mut opt.tar_prefix)
+            .add_option(&["--tar-prefix"], ParseOption,
+                "Top-level directory to put files into in a tarball \
+                 (`--format tar`). Overrides the one in config.");
+        ap.refer(&mut opt.packages)
+            .add_option(&["-p", "--package"], Collect,
+                "Build only this package from the config. May be \
+                 specified multiple times. By default all packages \
+                 are built.");
+        ap.refer(&mut opt.include)
+            .add_option(&["--include"], Collect,
+                "Put only files matching this glob into the package. \
+                 May be specified multiple times, added to the list \
+                 in config.");
+        ap.refer(&mut opt.exclude)
+            .add_option(&["--exclude"], Collect,
+                "Don't put files matching this glob into the package. \
+                 May be specified multiple times, added to the list \
+                 in config.");
+        ap.refer(&mut opt.xattrs)
+            .add_option(&["--xattrs"], StoreTrue,
+                "Store extended attributes of files in the package");
+        ap.refer(&mut opt.mtime)
+            .add_option(&["--mtime"], StoreOption,
+                "Modification time of files: `fixed` (default) sets it \
+                 to SOURCE_DATE_EPOCH, `clamp` keeps real time of files \
+                 but not later than SOURCE_DATE_EPOCH. Overrides the \
+                 one in config.");
+        ap.refer(&mut opt.dbgsym)
+            .add_option(&["--dbgsym"], StoreTrue,
+                "Strip debugging information from binaries and put it \
+                 into a separate NAME-dbgsym deb package");
+        ap.refer(&mut opt.auto_depends)
+            .add_option(&["--auto-depends"], StoreTrue,
+                "Add dependencies on packages providing shared libraries \
+                 that binaries are linked with (deb only)");
+        ap.refer(&mut opt.lint)
+            .add_option(&["--lint"], StoreTrue,
+                "Check debian packages after they are written (same as \
+                 `bulk lint`) and fail if there are errors");
+        ap.refer(&mut opt.list)
+            .add_option(&["--list"], StoreTrue,
+                "Print files that would be packed and exit without \
+                 writing a package");
+        ap.refer(&mut opt.verify_reproducible)
+            .add_option(&["--verify-reproducible"], StoreTrue,
+                "Build each package twice and fail if the results \
+                 differ, printing differences member by member");
         match ap.parse(args, &mut stdout(), &mut stderr()) {
             Ok(()) => {}
             Err(x) => exit(x),
         }
     }
 
-    match _pack(&config, &dir, &destdir, version) {
+    match _pack(opt) {
         Ok(()) => {}
         Err(text) => {
             writeln!(&mut stderr(), "Error: {}", text).ok();
@@ -141,3 +711,100 @@ pub fn pack(args: Vec<String>) {
         }
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use std::fs::{File, create_dir_all, hard_link};
+    use std::io::{Read, Write};
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+    use std::str::from_utf8;
+    use tar;
+    use tempfile::tempdir;
+
+    use config::{Config, Compression};
+    use super::compress::{self, decompress};
+    use super::files::{self, Filter};
+    use super::write_deb;
+
+    fn control_files(deb: &[u8]) -> Vec<u8> {
+        let mut pos = 8;
+        while pos < deb.len() {
+            let head = &deb[pos..pos+60];
+            let size: usize = from_utf8(&head[48..58]).unwrap()
+                .trim().parse().unwrap();
+            let body = &deb[pos+60..pos+60+size];
+            if head.starts_with(b"control.tar.gz ") {
+                return decompress(body).unwrap();
+            }
+            pos += 60 + size + size % 2;
+        }
+        panic!("no control.tar.gz");
+    }
+
+    fn tar_file(data: &[u8], name: &str) -> String {
+        let mut arch = tar::Archive::new(data);
+        for entry in arch.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry.path().unwrap() == Path::new(name) {
+                let mut buf = String::new();
+                entry.read_to_string(&mut buf).unwrap();
+                return buf;
+            }
+        }
+        panic!("no {} in archive", name);
+    }
+
+    #[test]
+    fn md5sums_and_size() {
+        let meta = Config::parse_string("minimum-bulk: v0.4.5\n\
+            metadata:\n  \
+              name: hello\n  \
+              short-description: hello world\n  \
+              long-description: prints hello world\n")
+            .unwrap().metadata.unwrap();
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("pkg");
+        create_dir_all(dir.join("usr/bin")).unwrap();
+        create_dir_all(dir.join("usr/share/doc/hello")).unwrap();
+        create_dir_all(dir.join("etc")).unwrap();
+        File::create(dir.join("usr/bin/hello")).unwrap()
+            .write_all(b"hello").unwrap();
+        File::create(dir.join("usr/share/doc/hello/README")).unwrap()
+            .write_all(&[b'x'; 2000]).unwrap();
+        File::create(dir.join("etc/hello.conf")).unwrap()
+            .write_all(b"greeting=hello\n").unwrap();
+        hard_link(dir.join("usr/bin/hello"), dir.join("usr/bin/hello2"))
+            .unwrap();
+        symlink("hello", dir.join("usr/bin/hi")).unwrap();
+        let options = files::Options {
+            filter: Filter::new(&[], &[]).unwrap(),
+            xattrs: false,
+            mtime: 1,
+            clamp_mtime: None,
+        };
+        let files = files::collect(&dir, &meta, &options).unwrap();
+        let mut deb = Vec::new();
+        let settings = compress::Settings {
+            kind: Compression::gzip,
+            level: None,
+            jobs: 1,
+        };
+        write_deb(&mut deb, tmp.path(), &files, &meta,
+                  &String::from("1.0"), "amd64", settings, 1).unwrap();
+        let control = control_files(&deb);
+        // Conffile isn't in md5sums, hard link has the same checksum as
+        // its target, symlink has no checksum
+        assert_eq!(tar_file(&control, "md5sums"), "\
+            5d41402abc4b2a76b9719d911017c592  usr/bin/hello\n\
+            5d41402abc4b2a76b9719d911017c592  usr/bin/hello2\n\
+            6284398f25b31fbdd31e5c6cc04af9ad  usr/share/doc/hello/README\n");
+        assert_eq!(tar_file(&control, "conffiles"), "/etc/hello.conf\n");
+        // 1 KiB per file (2 for README), 1 KiB per directory (etc, usr,
+        // usr/bin, usr/share, usr/share/doc, usr/share/doc/hello) and
+        // a symlink, nothing for a hard link
+        let text = tar_file(&control, "control");
+        assert!(text.contains("\nInstalled-Size: 11\n"), "{}", text);
+    }
+}
@@ -0,0 +1,463 @@
+use std::io::{self, Write, Seek, SeekFrom, BufWriter};
+use std::path::Path;
+use std::collections::HashMap;
+use std::os::unix::ffi::OsStrExt;
+
+use md5;
+use sha2::{Sha256, Digest};
+use tar::{Builder as Archive};
+use tempfile::tempfile_in;
+
+use config::Metadata;
+use super::compress;
+use super::convert::{parse_simple_relations, split_version, Relation};
+use super::deb::{read_scripts, script_call, conffiles};
+use super::files::{Entry, Kind};
+use super::permissions::Attributes;
+use super::tar::ArchiveExt;
+
+
+/// Converts debian architecture name to the one used in Arch Linux
+fn pacman_arch(arch: &str) -> &str {
+    match arch {
+        "all" => "any",
+        "amd64" => "x86_64",
+        "i386" => "i686",
+        "arm64" => "aarch64",
+        "armhf" => "armv7h",
+        "armel" => "arm",
+        _ => arch,
+    }
+}
+
+/// Returns full version as `[epoch:]version-release`
+fn pacman_version(version: &str) -> String {
+    match split_version(version) {
+        (Some(epoch), ver, rel) => format!("{}:{}-{}", epoch, ver, rel),
+        (None, ver, rel) => format!("{}-{}", ver, rel),
+    }
+}
+
+/// Returns file name of the package (without directory)
+pub fn package_name(meta: &Metadata, version: &str, arch: &str) -> String {
+    let (_, ver, rel) = split_version(version);
+    format!("{}-{}-{}-{}.pkg.tar.zst",
+        meta.name, ver, rel, pacman_arch(arch))
+}
+
+/// Escapes path for mtree the same way as libarchive does
+fn mtree_escape(path: &Path) -> String {
+    let mut result = String::new();
+    for &byte in path.as_os_str().as_bytes() {
+        if byte <= b' ' || byte >= 0x7f ||
+            byte == b'#' || byte == b'=' || byte == b'\\'
+        {
+            result.push_str(&format!("\\{:03o}", byte));
+        } else {
+            result.push(byte as char);
+        }
+    }
+    return result;
+}
+
+fn mtree_path(path: &Path) -> String {
+    format!("./{}", mtree_escape(path))
+}
+
+fn relations(field: &str, value: &Option<String>)
+    -> io::Result<Vec<Relation>>
+{
+    match *value {
+        Some(ref value) => parse_simple_relations(value, field, "pacman")
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData,
+                format!("can't convert {} to pacman: {}", field, e))),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Returns owner keywords for mtree, root is the default set in header
+fn mtree_owner(attrs: &Attributes) -> String {
+    let mut result = String::new();
+    if attrs.uid != 0 {
+        result.push_str(&format!(" uid={}", attrs.uid));
+    }
+    if attrs.gid != 0 {
+        result.push_str(&format!(" gid={}", attrs.gid));
+    }
+    if let Some(ref user) = attrs.user {
+        result.push_str(&format!(" uname={}", user));
+    }
+    if let Some(ref group) = attrs.group {
+        result.push_str(&format!(" gname={}", group));
+    }
+    return result;
+}
+
+/// Digests of a file as written to mtree
+#[derive(Clone)]
+struct Digests {
+    md5: md5::Digest,
+    sha256: String,
+}
+
+impl Digests {
+    fn of(data: &[u8]) -> Digests {
+        let mut sha256 = Sha256::new();
+        sha256.input(data);
+        Digests {
+            md5: md5::compute(data),
+            sha256: format!("{:x}", sha256.result()),
+        }
+    }
+}
+
+fn mtree_file(out: &mut Vec<u8>, name: &Path, mtime: u32,
+    mode: u32, owner: &str, size: u64, digests: &Digests)
+    -> io::Result<()>
+{
+    writeln!(out, "{} time={}.0 mode={:o}{} size={} md5digest={:x} \
+                   sha256digest={}",
+        mtree_path(name), mtime, mode & 0o7777, owner, size,
+        digests.md5, digests.sha256)
+}
+
+/// Writes shell function for pacman hook that runs the maintainer script
+///
+/// The script is fed to its interpreter through stdin, `args` are
+/// arguments debian would pass to the script.
+fn write_install(out: &mut Vec<u8>, function: &str, args: &str,
+    script: &[u8])
+{
+    out.extend(format!("{}() {{\n", function).as_bytes());
+    out.extend(script_call(args, script));
+    out.extend(b"}\n\n");
+}
+
+/// Writes Arch Linux package (`.pkg.tar.zst`)
+///
+/// Debian maintainer scripts are embedded into `.INSTALL` and run with
+/// interpreter from the shebang line.
+pub fn write_pacman<W: Write>(out: W, tmpdir: &Path, files: &[Entry],
+    meta: &Metadata, version: &str, architecture: &str,
+    compression: compress::Settings, mtime: u32)
+    -> Result<(), io::Error>
+{
+    let scripts = try!(read_scripts(&meta.scripts));
+    let conffiles = try!(conffiles(files, &meta.conffiles));
+
+    // Files are written first into a temporary (uncompressed) archive, so
+    // that each file is read once and digests are known for .MTREE, which
+    // precedes the files in the package
+    let mut data_file = try!(tempfile_in(tmpdir));
+    let mut digests = HashMap::new();
+    {
+        let mut arch = Archive::new(BufWriter::new(&mut data_file));
+        for entry in files {
+            let mut sha256 = Sha256::new();
+            if let Some(sum) = arch.append_entry_digest(entry, &mut sha256)? {
+                digests.insert(&entry.name, Digests {
+                    md5: sum.md5,
+                    sha256: format!("{:x}", sha256.result()),
+                });
+            }
+        }
+        arch.into_inner()?.flush()?;
+    }
+    try!(data_file.seek(SeekFrom::Start(0)));
+
+    let mut mtree = Vec::new();
+    writeln!(&mut mtree, "#mtree")?;
+    writeln!(&mut mtree, "/set type=file uid=0 gid=0 mode=644")?;
+    let mut size = 0;
+    let mut entries = Vec::new();
+    for entry in files {
+        let name = entry.name.as_path();
+        let owner = mtree_owner(&entry.attrs);
+        match entry.kind {
+            Kind::File { size: fsize } => {
+                size += fsize;
+                let mut buf = Vec::new();
+                try!(mtree_file(&mut buf, name, entry.mtime, entry.mode,
+                                &owner, fsize, &digests[&entry.name]));
+                entries.push(buf);
+            }
+            // Content of the hard link is the content of its target
+            // (source of the link itself may be not the same, i.e. when
+            // debugging symbols are stripped from the target)
+            Kind::HardLink { ref target, size: fsize } => {
+                let mut buf = Vec::new();
+                try!(mtree_file(&mut buf, name, entry.mtime, entry.mode,
+                                &owner, fsize, &digests[target]));
+                entries.push(buf);
+            }
+            Kind::Symlink(ref link) => {
+                entries.push(format!("{} time={}.0 mode={:o}{} type=link \
+                                      link={}\n",
+                    mtree_path(name), entry.mtime, entry.mode, owner,
+                    mtree_escape(link)).into_bytes());
+            }
+            Kind::Dir => {
+                entries.push(format!("{} time={}.0 mode={:o}{} type=dir\n",
+                    mtree_path(name), entry.mtime, entry.mode,
+                    owner).into_bytes());
+            }
+            Kind::Fifo => {
+                entries.push(format!("{} time={}.0 mode={:o}{} type=fifo\n",
+                    mtree_path(name), entry.mtime, entry.mode,
+                    owner).into_bytes());
+            }
+            Kind::CharDevice { major, minor } |
+            Kind::BlockDevice { major, minor } => {
+                let typ = match entry.kind {
+                    Kind::CharDevice { .. } => "char",
+                    _ => "block",
+                };
+                entries.push(format!("{} time={}.0 mode={:o}{} type={} \
+                                      device=native,{},{}\n",
+                    mtree_path(name), entry.mtime, entry.mode, owner, typ,
+                    major, minor).into_bytes());
+            }
+        }
+    }
+
+    let mut pkginfo = Vec::new();
+    writeln!(&mut pkginfo, "# Generated by bulk {}",
+        env!("CARGO_PKG_VERSION"))?;
+    writeln!(&mut pkginfo, "pkgname = {}", meta.name)?;
+    writeln!(&mut pkginfo, "pkgbase = {}", meta.name)?;
+    writeln!(&mut pkginfo, "pkgver = {}", pacman_version(version))?;
+    writeln!(&mut pkginfo, "pkgdesc = {}", meta.short_description)?;
+    if let Some(ref homepage) = meta.homepage {
+        writeln!(&mut pkginfo, "url = {}", homepage)?;
+    }
+    writeln!(&mut pkginfo, "builddate = {}", mtime)?;
+    writeln!(&mut pkginfo, "packager = {}",
+        meta.maintainer.as_ref().map(|x| &x[..])
+        .unwrap_or("Unknown Packager"))?;
+    writeln!(&mut pkginfo, "size = {}", size)?;
+    writeln!(&mut pkginfo, "arch = {}", pacman_arch(architecture))?;
+    for rel in try!(relations("replaces", &meta.replaces)) {
+        writeln!(&mut pkginfo, "replaces = {}", rel.compact())?;
+    }
+    for rel in try!(relations("conflicts", &meta.conflicts)).into_iter()
+        .chain(try!(relations("breaks", &meta.breaks)))
+    {
+        writeln!(&mut pkginfo, "conflict = {}", rel.compact())?;
+    }
+    for rel in try!(relations("provides", &meta.provides)) {
+        writeln!(&mut pkginfo, "provides = {}", rel.compact())?;
+    }
+    for path in &conffiles {
+        writeln!(&mut pkginfo, "backup = {}",
+            path.strip_prefix("/").unwrap().display())?;
+    }
+    for rel in try!(relations("pre-depends", &meta.pre_depends)).into_iter()
+        .chain(try!(relations("depends", &meta.depends)))
+    {
+        writeln!(&mut pkginfo, "depend = {}", rel.compact())?;
+    }
+    for rel in try!(relations("recommends", &meta.recommends)).into_iter()
+        .chain(try!(relations("suggests", &meta.suggests)))
+    {
+        writeln!(&mut pkginfo, "optdepend = {}", rel.compact())?;
+    }
+
+    let mut install = Vec::new();
+    for &(name, ref data) in &scripts {
+        // Pacman passes new version and old version on upgrade (or just
+        // old version on removal), debian scripts get an action and the
+        // old version on upgrade
+        let functions: &[(&str, &str)] = match name {
+            "preinst" => &[("pre_install", "install"),
+                           ("pre_upgrade", "upgrade \"$2\"")],
+            "postinst" => &[("post_install", "configure"),
+                            ("post_upgrade", "configure \"$2\"")],
+            "prerm" => &[("pre_remove", "remove")],
+            "postrm" => &[("post_remove", "remove")],
+            _ => unreachable!(),
+        };
+        for &(function, args) in functions {
+            write_install(&mut install, function, args, data);
+        }
+    }
+
+    // .MTREE lists metadata files too, so it's assembled last
+    let mut meta_entries = Vec::new();
+    try!(mtree_file(&mut meta_entries, Path::new(".PKGINFO"), mtime, 0o644,
+        "", pkginfo.len() as u64, &Digests::of(&pkginfo)));
+    if install.len() > 0 {
+        try!(mtree_file(&mut meta_entries, Path::new(".INSTALL"), mtime,
+            0o644, "", install.len() as u64, &Digests::of(&install)));
+    }
+    mtree.extend(meta_entries);
+    for entry in entries {
+        mtree.extend(entry);
+    }
+    let mut mtree_gz = compress::gzip_encoder(Vec::new())?;
+    mtree_gz.write_all(&mtree)?;
+    let mtree = mtree_gz.finish().into_result()?;
+
+    let mut control = Vec::new();
+    {
+        let mut arch = Archive::new(&mut control);
+        arch.append_blob(".PKGINFO", mtime, 0o644, &pkginfo)?;
+        if install.len() > 0 {
+            arch.append_blob(".INSTALL", mtime, 0o644, &install)?;
+        }
+        arch.append_blob(".MTREE", mtime, 0o644, &mtree)?;
+        arch.finish()?;
+    }
+    // Metadata files are followed by files in the same archive, so
+    // end-of-archive marker (two zero blocks) is stripped
+    let control_len = control.len() - 1024;
+    control.truncate(control_len);
+
+    let mut out = compression.encoder(BufWriter::new(out))?;
+    out.write_all(&control)?;
+    try!(io::copy(&mut data_file, &mut out));
+    out.finish()?.flush()?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::fs::{File, create_dir_all, hard_link};
+    use std::fs::{set_permissions, Permissions};
+    use std::io::{Read, Write};
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use tar;
+    use tempfile::{tempdir, TempDir};
+
+    use config::{Config, Metadata, Compression};
+    use pack::compress::{self, decompress};
+    use pack::files::{self, Entry, Filter};
+    use super::write_pacman;
+
+    const HELLO_MD5: &str = "5d41402abc4b2a76b9719d911017c592";
+    const HELLO_SHA256: &str = "\
+        2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    fn text(data: Vec<u8>) -> String {
+        String::from_utf8(data).unwrap()
+    }
+
+    fn metadata() -> Metadata {
+        Config::parse_string("minimum-bulk: v0.4.5\n\
+            metadata:\n  \
+              name: hello\n  \
+              short-description: hello world\n  \
+              long-description: prints hello world\n  \
+              maintainer: Jane Doe <jane@example.com>\n  \
+              depends: libc6 (>= 2.0)\n  \
+              suggests: bash\n")
+            .unwrap().metadata.unwrap()
+    }
+
+    /// Collects a binary, its hard link, a symlink and a config
+    fn tree(meta: &Metadata) -> (TempDir, Vec<Entry>) {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("pkg");
+        create_dir_all(dir.join("usr/bin")).unwrap();
+        create_dir_all(dir.join("etc")).unwrap();
+        File::create(dir.join("usr/bin/hello")).unwrap()
+            .write_all(b"hello").unwrap();
+        set_permissions(dir.join("usr/bin/hello"),
+                        Permissions::from_mode(0o755)).unwrap();
+        File::create(dir.join("etc/hello.conf")).unwrap()
+            .write_all(b"greeting=hello\n").unwrap();
+        hard_link(dir.join("usr/bin/hello"), dir.join("usr/bin/hello2"))
+            .unwrap();
+        symlink("hello", dir.join("usr/bin/hi")).unwrap();
+        let options = files::Options {
+            filter: Filter::new(&[], &[]).unwrap(),
+            xattrs: false,
+            mtime: 1,
+            clamp_mtime: None,
+        };
+        let entries = files::collect(&dir, meta, &options).unwrap();
+        (tmp, entries)
+    }
+
+    fn tar_file(data: &[u8], name: &str) -> Option<Vec<u8>> {
+        let mut arch = tar::Archive::new(data);
+        for entry in arch.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry.path().unwrap().to_str() == Some(name) {
+                let mut buf = Vec::new();
+                entry.read_to_end(&mut buf).unwrap();
+                return Some(buf);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn package() {
+        let mut meta = metadata();
+        meta.scripts.postinst = Some(String::from("#!/bin/sh -e\nldconfig"));
+        let (dir, mut files) = tree(&meta);
+        // Hard link must have the content of its target, even if the
+        // source of the link itself differs (e.g. target is stripped)
+        let other = dir.path().join("other");
+        File::create(&other).unwrap().write_all(b"other").unwrap();
+        files.iter_mut().find(|e| e.name.ends_with("hello2")).unwrap()
+            .source = Some(other);
+        let settings = compress::Settings {
+            kind: Compression::zstd,
+            level: None,
+            jobs: 1,
+        };
+        let tmp = tempdir().unwrap();
+        let mut buf = Vec::new();
+        write_pacman(&mut buf, tmp.path(), &files, &meta, "1:1.0-2",
+                     "amd64", settings, 1).unwrap();
+        let data = decompress(&buf).unwrap();
+
+        assert_eq!(text(tar_file(&data, ".PKGINFO").unwrap()), format!("\
+            # Generated by bulk {}\n\
+            pkgname = hello\n\
+            pkgbase = hello\n\
+            pkgver = 1:1.0-2\n\
+            pkgdesc = hello world\n\
+            builddate = 1\n\
+            packager = Jane Doe <jane@example.com>\n\
+            size = 20\n\
+            arch = x86_64\n\
+            backup = etc/hello.conf\n\
+            depend = libc6>=2.0\n\
+            optdepend = bash\n",
+            env!("CARGO_PKG_VERSION")));
+
+        assert_eq!(text(tar_file(&data, ".INSTALL").unwrap()), "\
+            post_install() {\n\
+            /bin/sh -e /dev/stdin configure <<'__BULK_SCRIPT_END__'\n\
+            #!/bin/sh -e\nldconfig\n\
+            __BULK_SCRIPT_END__\n}\n\n\
+            post_upgrade() {\n\
+            /bin/sh -e /dev/stdin configure \"$2\" \
+                <<'__BULK_SCRIPT_END__'\n\
+            #!/bin/sh -e\nldconfig\n\
+            __BULK_SCRIPT_END__\n}\n\n");
+
+        let mtree = text(decompress(&tar_file(&data, ".MTREE").unwrap())
+                         .unwrap());
+        let lines = mtree.lines().collect::<Vec<_>>();
+        assert_eq!(lines[..2], ["#mtree",
+                                "/set type=file uid=0 gid=0 mode=644"]);
+        assert!(lines[2].starts_with("./.PKGINFO time=1.0 mode=644 size="));
+        assert!(lines[3].starts_with("./.INSTALL time=1.0 mode=644 size="));
+        assert!(lines.contains(&&format!("./usr/bin/hello time=1.0 \
+            mode=755 size=5 md5digest={} sha256digest={}",
+            HELLO_MD5, HELLO_SHA256)[..]));
+        assert!(lines.contains(&&format!("./usr/bin/hello2 time=1.0 \
+            mode=755 size=5 md5digest={} sha256digest={}",
+            HELLO_MD5, HELLO_SHA256)[..]));
+        assert!(lines.contains(
+            &"./usr/bin/hi time=1.0 mode=777 type=link link=hello"));
+        assert!(lines.contains(&"./usr/bin time=1.0 mode=755 type=dir"));
+
+        // Files follow metadata in a single archive
+        assert_eq!(tar_file(&data, "usr/bin/hello").unwrap(), b"hello");
+    }
+}
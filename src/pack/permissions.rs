@@ -0,0 +1,162 @@
+use std::io;
+use std::path::Path;
+
+use glob::{Pattern, MatchOptions};
+
+use config::{Permissions, parse_mode};
+use super::caps;
+
+
+/// Owner and mode of a file in a package
+///
+/// Files are owned by root unless overriden in config. User and group
+/// names are empty by default, which means numeric ids should be used.
+#[derive(Debug, Clone, Default)]
+pub struct Attributes {
+    pub uid: u32,
+    pub gid: u32,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub mode: Option<u32>,
+    /// Value of `security.capability` extended attribute
+    pub capabilities: Option<Vec<u8>>,
+}
+
+/// Compiled `permissions` section of the config
+pub struct Rules {
+    rules: Vec<(Pattern, Permissions, Option<u32>, Option<Vec<u8>>)>,
+}
+
+impl Attributes {
+    /// Applies mode override to the mode from the filesystem
+    ///
+    /// File type bits of the original mode are kept
+    pub fn mode(&self, original: u32) -> u32 {
+        match self.mode {
+            Some(mode) => (original & !0o7777) | mode,
+            None => original,
+        }
+    }
+    /// Returns true if anything is overriden in config
+    pub fn is_overriden(&self) -> bool {
+        self.uid != 0 || self.gid != 0 || self.user.is_some() ||
+            self.group.is_some() || self.mode.is_some()
+    }
+}
+
+impl Rules {
+    pub fn new(config: &[Permissions]) -> io::Result<Rules> {
+        let mut rules = Vec::new();
+        for perm in config {
+            let pattern = try!(Pattern::new(&perm.path)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData,
+                    format!("invalid glob {:?}: {}", perm.path, e))));
+            let mode = match perm.mode {
+                Some(ref mode) => Some(try!(parse_mode(mode)
+                    .map_err(|e| io::Error::new(
+                        io::ErrorKind::InvalidData, e)))),
+                None => None,
+            };
+            let capabilities = match perm.capabilities {
+                Some(ref text) => Some(try!(caps::encode(text)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData,
+                        format!("invalid capabilities {:?}: {}", text, e))))),
+                None => None,
+            };
+            rules.push((pattern, perm.clone(), mode, capabilities));
+        }
+        Ok(Rules { rules: rules })
+    }
+    /// Returns attributes for a path relative to the package root
+    ///
+    /// All matching rules are applied in order, so later ones override
+    /// fields set by earlier ones.
+    pub fn attributes(&self, path: &Path) -> Attributes {
+        let mut attrs = Attributes::default();
+        if self.rules.len() == 0 {
+            return attrs;
+        }
+        let path = Path::new("/").join(path);
+        let options = MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        for &(ref pattern, ref perm, mode, ref capabilities) in &self.rules {
+            if !pattern.matches_path_with(&path, &options) {
+                continue;
+            }
+            if let Some(uid) = perm.uid {
+                attrs.uid = uid;
+            }
+            if let Some(gid) = perm.gid {
+                attrs.gid = gid;
+            }
+            if perm.user.is_some() {
+                attrs.user = perm.user.clone();
+            }
+            if perm.group.is_some() {
+                attrs.group = perm.group.clone();
+            }
+            if mode.is_some() {
+                attrs.mode = mode;
+            }
+            if capabilities.is_some() {
+                attrs.capabilities = capabilities.clone();
+            }
+        }
+        return attrs;
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use config::Permissions;
+    use super::Rules;
+
+    fn perm(path: &str, user: Option<&str>, uid: Option<u32>,
+        mode: Option<&str>)
+        -> Permissions
+    {
+        Permissions {
+            path: path.to_string(),
+            user: user.map(String::from),
+            group: None,
+            uid: uid,
+            gid: None,
+            mode: mode.map(String::from),
+            capabilities: None,
+        }
+    }
+
+    #[test]
+    fn default_root() {
+        let rules = Rules::new(&[]).unwrap();
+        let attrs = rules.attributes(Path::new("usr/bin/x"));
+        assert_eq!(attrs.uid, 0);
+        assert_eq!(attrs.user, None);
+        assert_eq!(attrs.mode(0o100644), 0o100644);
+    }
+
+    #[test]
+    fn globs() {
+        let rules = Rules::new(&[
+            perm("/var/lib/app/**", Some("app"), Some(120), None),
+            perm("/var/lib/app/*.key", None, None, Some("0600")),
+        ]).unwrap();
+        let attrs = rules.attributes(Path::new("var/lib/app/x/y"));
+        assert_eq!(attrs.uid, 120);
+        assert_eq!(attrs.user, Some(String::from("app")));
+        assert_eq!(attrs.mode, None);
+        let attrs = rules.attributes(Path::new("var/lib/app/secret.key"));
+        assert_eq!(attrs.uid, 120);
+        assert_eq!(attrs.mode(0o100644), 0o100600);
+        // `*` doesn't match slash
+        let attrs = rules.attributes(Path::new("var/lib/app/x/secret.key"));
+        assert_eq!(attrs.mode, None);
+        let attrs = rules.attributes(Path::new("var/lib/other"));
+        assert_eq!(attrs.uid, 0);
+    }
+}
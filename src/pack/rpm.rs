@@ -0,0 +1,922 @@
+use std::io::{self, Read, Write, Seek, SeekFrom, BufWriter, stderr};
+use std::fs::File;
+use std::path::Path;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::os::unix::ffi::OsStrExt;
+
+use md5;
+use sha2::{Sha256, Digest};
+use tempfile::tempfile_in;
+
+use config::{Metadata, Compression};
+use hash_file::Md5Reader;
+use super::compress;
+use super::caps;
+use super::cpio;
+use super::deb::{read_scripts, script_call, conffiles};
+use super::files::{Entry, Kind};
+use super::convert::{parse_relations, split_version, Op};
+
+
+const HEADER_MAGIC: &[u8] = b"\x8e\xad\xe8\x01\0\0\0\0";
+
+const HEADERSIGNATURES: u32 = 62;
+const HEADERIMMUTABLE: u32 = 63;
+const HEADERI18NTABLE: u32 = 100;
+
+const SIG_SHA256: u32 = 273;
+const SIG_SIZE: u32 = 1000;
+const SIG_MD5: u32 = 1004;
+const SIG_PAYLOADSIZE: u32 = 1007;
+
+const TAG_NAME: u32 = 1000;
+const TAG_VERSION: u32 = 1001;
+const TAG_RELEASE: u32 = 1002;
+const TAG_EPOCH: u32 = 1003;
+const TAG_SUMMARY: u32 = 1004;
+const TAG_DESCRIPTION: u32 = 1005;
+const TAG_BUILDTIME: u32 = 1006;
+const TAG_SIZE: u32 = 1009;
+const TAG_PACKAGER: u32 = 1015;
+const TAG_GROUP: u32 = 1016;
+const TAG_URL: u32 = 1020;
+const TAG_OS: u32 = 1021;
+const TAG_ARCH: u32 = 1022;
+const TAG_PREIN: u32 = 1023;
+const TAG_POSTIN: u32 = 1024;
+const TAG_PREUN: u32 = 1025;
+const TAG_POSTUN: u32 = 1026;
+const TAG_FILESIZES: u32 = 1028;
+const TAG_FILEMODES: u32 = 1030;
+const TAG_FILERDEVS: u32 = 1033;
+const TAG_FILEMTIMES: u32 = 1034;
+const TAG_FILEDIGESTS: u32 = 1035;
+const TAG_FILELINKTOS: u32 = 1036;
+const TAG_FILEFLAGS: u32 = 1037;
+const TAG_FILEUSERNAME: u32 = 1039;
+const TAG_FILEGROUPNAME: u32 = 1040;
+const TAG_SOURCERPM: u32 = 1044;
+const TAG_FILEVERIFYFLAGS: u32 = 1045;
+const TAG_PROVIDENAME: u32 = 1047;
+const TAG_REQUIREFLAGS: u32 = 1048;
+const TAG_REQUIRENAME: u32 = 1049;
+const TAG_REQUIREVERSION: u32 = 1050;
+const TAG_CONFLICTFLAGS: u32 = 1053;
+const TAG_CONFLICTNAME: u32 = 1054;
+const TAG_CONFLICTVERSION: u32 = 1055;
+const TAG_PREINPROG: u32 = 1085;
+const TAG_POSTINPROG: u32 = 1086;
+const TAG_PREUNPROG: u32 = 1087;
+const TAG_POSTUNPROG: u32 = 1088;
+const TAG_FILEDEVICES: u32 = 1095;
+const TAG_FILEINODES: u32 = 1096;
+const TAG_FILELANGS: u32 = 1097;
+const TAG_PROVIDEFLAGS: u32 = 1112;
+const TAG_PROVIDEVERSION: u32 = 1113;
+const TAG_DIRINDEXES: u32 = 1116;
+const TAG_BASENAMES: u32 = 1117;
+const TAG_DIRNAMES: u32 = 1118;
+const TAG_PAYLOADFORMAT: u32 = 1124;
+const TAG_PAYLOADCOMPRESSOR: u32 = 1125;
+const TAG_PAYLOADFLAGS: u32 = 1126;
+const TAG_FILECAPS: u32 = 5010;
+const TAG_RECOMMENDNAME: u32 = 5046;
+const TAG_RECOMMENDVERSION: u32 = 5047;
+const TAG_RECOMMENDFLAGS: u32 = 5048;
+const TAG_SUGGESTNAME: u32 = 5049;
+const TAG_SUGGESTVERSION: u32 = 5050;
+const TAG_SUGGESTFLAGS: u32 = 5051;
+
+const SENSE_LESS: u32 = 1 << 1;
+const SENSE_GREATER: u32 = 1 << 2;
+const SENSE_EQUAL: u32 = 1 << 3;
+const SENSE_PREREQ: u32 = 1 << 6;
+const SENSE_RPMLIB: u32 = 1 << 24;
+
+const FILE_CONFIG: u32 = 1 << 0;
+const FILE_NOREPLACE: u32 = 1 << 4;
+
+
+enum Value {
+    Int16(Vec<u16>),
+    Int32(Vec<u32>),
+    Str(String),
+    Bin(Vec<u8>),
+    StrArray(Vec<String>),
+    I18nStr(String),
+}
+
+/// Header structure, used both for the signature and the main header
+struct Header {
+    region: u32,
+    entries: BTreeMap<u32, Value>,
+}
+
+/// Single dependency in rpm terms
+struct Dependency {
+    name: String,
+    flags: u32,
+    version: String,
+}
+
+impl Value {
+    fn type_and_count(&self) -> (u32, usize) {
+        match *self {
+            Value::Int16(ref x) => (3, x.len()),
+            Value::Int32(ref x) => (4, x.len()),
+            Value::Str(_) => (6, 1),
+            Value::Bin(ref x) => (7, x.len()),
+            Value::StrArray(ref x) => (8, x.len()),
+            Value::I18nStr(_) => (9, 1),
+        }
+    }
+    fn alignment(&self) -> usize {
+        match *self {
+            Value::Int16(_) => 2,
+            Value::Int32(_) => 4,
+            _ => 1,
+        }
+    }
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        match *self {
+            Value::Int16(ref items) => {
+                for x in items {
+                    buf.extend(&[(x >> 8) as u8, *x as u8]);
+                }
+            }
+            Value::Int32(ref items) => {
+                for x in items {
+                    buf.extend(&be32(*x));
+                }
+            }
+            Value::Str(ref x) | Value::I18nStr(ref x) => {
+                buf.extend(x.as_bytes());
+                buf.push(0);
+            }
+            Value::Bin(ref x) => buf.extend(x),
+            Value::StrArray(ref items) => {
+                for x in items {
+                    buf.extend(x.as_bytes());
+                    buf.push(0);
+                }
+            }
+        }
+    }
+}
+
+fn be32(x: u32) -> [u8; 4] {
+    [(x >> 24) as u8, (x >> 16) as u8, (x >> 8) as u8, x as u8]
+}
+
+impl Header {
+    fn new(region: u32) -> Header {
+        Header {
+            region: region,
+            entries: BTreeMap::new(),
+        }
+    }
+    fn add(&mut self, tag: u32, value: Value) {
+        self.entries.insert(tag, value);
+    }
+    fn add_str<S: AsRef<str>>(&mut self, tag: u32, value: S) {
+        self.add(tag, Value::Str(value.as_ref().to_string()));
+    }
+    /// Serializes header including immutable region tag
+    ///
+    /// Data is written in the same order as index entries and region
+    /// trailer is the last item in the data store, as rpm requires.
+    fn to_bytes(&self) -> Vec<u8> {
+        let count = self.entries.len() + 1;
+        let mut index = Vec::with_capacity(count*16);
+        let mut data = Vec::new();
+        for (tag, value) in &self.entries {
+            let align = value.alignment();
+            while data.len() % align != 0 {
+                data.push(0);
+            }
+            let (typ, num) = value.type_and_count();
+            index.extend(&be32(*tag));
+            index.extend(&be32(typ));
+            index.extend(&be32(data.len() as u32));
+            index.extend(&be32(num as u32));
+            value.write_to(&mut data);
+        }
+        let trailer_offset = data.len() as u32;
+        data.extend(&be32(self.region));
+        data.extend(&be32(7));
+        data.extend(&be32((-((count*16) as i32)) as u32));
+        data.extend(&be32(16));
+
+        let mut buf = Vec::with_capacity(16 + index.len() + data.len());
+        buf.extend(HEADER_MAGIC);
+        buf.extend(&be32(count as u32));
+        buf.extend(&be32(data.len() as u32));
+        buf.extend(&be32(self.region));
+        buf.extend(&be32(7));
+        buf.extend(&be32(trailer_offset));
+        buf.extend(&be32(16));
+        buf.extend(&index);
+        buf.extend(&data);
+        return buf;
+    }
+}
+
+/// Converts debian architecture name to the rpm one
+fn rpm_arch(arch: &str) -> &str {
+    match arch {
+        "all" => "noarch",
+        "amd64" => "x86_64",
+        "i386" => "i686",
+        "arm64" => "aarch64",
+        "armhf" => "armv7hl",
+        "armel" => "armv5tel",
+        "ppc64el" => "ppc64le",
+        _ => arch,
+    }
+}
+
+fn lead_arch(arch: &str) -> u16 {
+    match arch {
+        "x86_64" | "i686" => 1,
+        "aarch64" => 19,
+        "ppc64le" | "ppc64" => 16,
+        "s390x" => 15,
+        "armv7hl" | "armv5tel" => 12,
+        _ => 0,
+    }
+}
+
+/// Returns file name of the package (without directory)
+pub fn package_name(meta: &Metadata, version: &str, arch: &str) -> String {
+    let (_, version, release) = split_version(version);
+    format!("{}-{}-{}.{}.rpm", meta.name, version, release, rpm_arch(arch))
+}
+
+fn flags(op: Op) -> u32 {
+    match op {
+        Op::Less => SENSE_LESS,
+        Op::LessEq => SENSE_LESS|SENSE_EQUAL,
+        Op::Eq => SENSE_EQUAL,
+        Op::GreaterEq => SENSE_GREATER|SENSE_EQUAL,
+        Op::Greater => SENSE_GREATER,
+    }
+}
+
+/// Converts debian dependency field into a list of rpm dependencies
+///
+/// Alternatives (`a | b`) are converted into rich dependencies `(a or b)`.
+fn parse_dependencies(field: &str, value: &str)
+    -> Result<Vec<Dependency>, io::Error>
+{
+    let relations = try!(parse_relations(value)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData,
+            format!("can't convert {} to rpm: {}", field, e))));
+    Ok(relations.into_iter().map(|mut alternatives| {
+        if alternatives.len() == 1 {
+            let rel = alternatives.pop().unwrap();
+            let (flags, version) = match rel.version {
+                Some((op, ver)) => (flags(op), ver),
+                None => (0, String::new()),
+            };
+            Dependency {
+                name: rel.name,
+                flags: flags,
+                version: version,
+            }
+        } else {
+            let rich = alternatives.iter()
+                .map(|r| match r.version {
+                    Some((op, ref ver))
+                    => format!("{} {} {}", r.name, op.symbol(), ver),
+                    None => r.name.clone(),
+                })
+                .collect::<Vec<_>>().join(" or ");
+            Dependency {
+                name: format!("({})", rich),
+                flags: 0,
+                version: String::new(),
+            }
+        }
+    }).collect())
+}
+
+fn add_dependencies(header: &mut Header, tags: (u32, u32, u32),
+    deps: Vec<Dependency>)
+{
+    if deps.len() == 0 {
+        return;
+    }
+    let (name_tag, flags_tag, version_tag) = tags;
+    header.add(flags_tag,
+        Value::Int32(deps.iter().map(|d| d.flags).collect()));
+    header.add(name_tag,
+        Value::StrArray(deps.iter().map(|d| d.name.clone()).collect()));
+    header.add(version_tag,
+        Value::StrArray(deps.into_iter().map(|d| d.version).collect()));
+}
+
+fn rpmlib(name: &str, version: &str) -> Dependency {
+    Dependency {
+        name: format!("rpmlib({})", name),
+        flags: SENSE_RPMLIB|SENSE_LESS|SENSE_EQUAL,
+        version: version.to_string(),
+    }
+}
+
+struct FileEntry {
+    path: String,
+    size: u32,
+    mode: u16,
+    mtime: u32,
+    link: String,
+    digest: String,
+    flags: u32,
+    user: String,
+    group: String,
+    inode: u32,
+    rdev: u16,
+    capabilities: String,
+}
+
+/// Rpm stores only names of the owners, so they must be known
+fn owner_name(name: &Option<String>, id: u32, kind: &str, path: &Path)
+    -> io::Result<String>
+{
+    match *name {
+        Some(ref name) => Ok(name.clone()),
+        None if id == 0 => Ok(String::from("root")),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("rpm requires {} name for {} {} of {:?}",
+                    kind, kind, id, path))),
+    }
+}
+
+pub fn write_rpm<W: Write>(out: W, tmpdir: &Path, files: &[Entry],
+    meta: &Metadata, version: &str, architecture: &str,
+    compression: compress::Settings, mtime: u32)
+    -> Result<(), io::Error>
+{
+    let compressor = match compression.kind {
+        Compression::gzip => "gzip",
+        Compression::xz => "xz",
+        Compression::zstd => "zstd",
+        Compression::none => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "rpm payload can't be uncompressed"));
+        }
+    };
+    let scripts = try!(read_scripts(&meta.scripts));
+    let conffiles = try!(conffiles(files, &meta.conffiles));
+    // rpm sorts files by full path as a string
+    let mut files = files.iter().collect::<Vec<_>>();
+    files.sort_by(|a, b| a.name.as_os_str().as_bytes()
+                  .cmp(b.name.as_os_str().as_bytes()));
+
+    let mut payload = try!(tempfile_in(tmpdir));
+    // Indexes of all links to each file, they share the inode
+    let mut links = HashMap::new();
+    for (idx, file) in files.iter().enumerate() {
+        match file.kind {
+            Kind::File { .. } => {
+                links.entry(file.name.as_path())
+                    .or_insert_with(Vec::new).push(idx);
+            }
+            Kind::HardLink { ref target, .. } => {
+                links.entry(target.as_path())
+                    .or_insert_with(Vec::new).push(idx);
+            }
+            _ => {}
+        }
+    }
+    let mut entries = Vec::new();
+    let mut digests = HashMap::new();
+    let mut dropped_xattrs = 0;
+    let payload_size = {
+        let mut cpio = cpio::Builder::new(
+            try!(compression.encoder(&mut payload)));
+        for (idx, file) in files.iter().enumerate() {
+            let ref attrs = file.attrs;
+            if file.is_dir() && !attrs.is_overriden() {
+                // Directories are created by rpm implicitly, and owning
+                // system directories leads to conflicts
+                continue;
+            }
+            let path = Path::new("/").join(&file.name);
+            let mode = file.mode | file.type_bits();
+            let link_set = match file.kind {
+                Kind::File { .. } => links.get(file.name.as_path()),
+                Kind::HardLink { ref target, .. } => {
+                    links.get(target.as_path())
+                }
+                _ => None,
+            };
+            let (rdev_major, rdev_minor) = match file.kind {
+                Kind::CharDevice { major, minor } |
+                Kind::BlockDevice { major, minor } => (major, minor),
+                _ => (0, 0),
+            };
+            if rdev_major > 0xff || rdev_minor > 0xff {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("device {:?} number is too large for rpm",
+                            file.name)));
+            }
+            let mut entry = FileEntry {
+                path: path.to_string_lossy().into_owned(),
+                size: 0,
+                mode: mode as u16,
+                mtime: file.mtime,
+                link: String::new(),
+                digest: String::new(),
+                flags: if conffiles.contains(&path) {
+                    FILE_CONFIG|FILE_NOREPLACE
+                } else {
+                    0
+                },
+                user: try!(owner_name(&attrs.user, attrs.uid, "user", &path)),
+                group: try!(owner_name(&attrs.group, attrs.gid, "group",
+                                       &path)),
+                inode: link_set.map(|s| s[0]).unwrap_or(idx) as u32 + 1,
+                rdev: (rdev_major << 8 | rdev_minor) as u16,
+                capabilities: file.xattrs.get(caps::XATTR_NAME)
+                    .and_then(|value| caps::decode(value))
+                    .unwrap_or_else(String::new),
+            };
+            if file.xattrs.keys().any(|name| name != caps::XATTR_NAME) {
+                dropped_xattrs += 1;
+            }
+            let head = cpio::Header {
+                ino: entry.inode,
+                mode: mode,
+                uid: attrs.uid,
+                gid: attrs.gid,
+                nlink: link_set.map(|s| s.len()).unwrap_or(1) as u32,
+                mtime: file.mtime,
+                rdev_major: rdev_major,
+                rdev_minor: rdev_minor,
+                .. cpio::Header::default()
+            };
+            let name = format!(".{}", entry.path);
+            match file.kind {
+                Kind::File { size } | Kind::HardLink { size, .. } => {
+                    if size > u32::max_value() as u64 {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                            format!("file {:?} is too large for rpm",
+                                    file.name)));
+                    }
+                    entry.size = size as u32;
+                    // Data of hard linked files is stored with the last link
+                    if link_set.and_then(|s| s.last()) == Some(&idx) {
+                        let head = cpio::Header { size: entry.size, .. head };
+                        let source = file.source.as_ref()
+                            .expect("file has source");
+                        let mut reader = Md5Reader::new(
+                            try!(File::open(source)).take(size));
+                        try!(cpio.append(&name, &head, &mut reader));
+                        digests.insert(entry.inode,
+                                       format!("{:x}", reader.digest()));
+                    } else {
+                        try!(cpio.append(&name, &head, io::empty()));
+                    }
+                }
+                Kind::Dir | Kind::Fifo |
+                Kind::CharDevice { .. } | Kind::BlockDevice { .. } => {
+                    try!(cpio.append(&name, &head, io::empty()));
+                }
+                Kind::Symlink(ref link) => {
+                    entry.link = link.to_string_lossy().into_owned();
+                    entry.size = entry.link.len() as u32;
+                    let head = cpio::Header { size: entry.size, .. head };
+                    try!(cpio.append(&name, &head, entry.link.as_bytes()));
+                }
+            }
+            entries.push(entry);
+        }
+        for entry in &mut entries {
+            if let Some(digest) = digests.get(&entry.inode) {
+                entry.digest = digest.clone();
+            }
+        }
+        let (encoder, size) = try!(cpio.finish());
+        try!(encoder.finish());
+        size
+    };
+    if dropped_xattrs > 0 {
+        writeln!(&mut stderr(), "Warning: rpm can only store capabilities, \
+            other extended attributes of {} files are dropped",
+            dropped_xattrs).ok();
+    }
+    if payload_size > u32::max_value() as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            "package is too large for rpm"));
+    }
+
+    let (epoch, rpm_ver, release) = split_version(version);
+    let arch = rpm_arch(architecture);
+    let mut header = Header::new(HEADERIMMUTABLE);
+    header.add(HEADERI18NTABLE, Value::StrArray(vec![String::from("C")]));
+    header.add_str(TAG_NAME, &meta.name);
+    header.add_str(TAG_VERSION, &rpm_ver);
+    header.add_str(TAG_RELEASE, &release);
+    if let Some(epoch) = epoch {
+        header.add(TAG_EPOCH, Value::Int32(vec![epoch]));
+    }
+    header.add(TAG_SUMMARY, Value::I18nStr(meta.short_description.clone()));
+    header.add(TAG_DESCRIPTION,
+        Value::I18nStr(meta.long_description.clone()));
+    header.add(TAG_BUILDTIME, Value::Int32(vec![mtime]));
+    // Hard links share an inode and their data is counted once
+    let mut inodes = HashSet::new();
+    header.add(TAG_SIZE, Value::Int32(vec![
+        entries.iter().filter(|e| inodes.insert(e.inode))
+            .map(|e| e.size as u64).sum::<u64>() as u32]));
+    if let Some(ref maintainer) = meta.maintainer {
+        header.add_str(TAG_PACKAGER, maintainer);
+    }
+    if let Some(ref section) = meta.section {
+        header.add(TAG_GROUP, Value::I18nStr(section.clone()));
+    }
+    if let Some(ref homepage) = meta.homepage {
+        header.add_str(TAG_URL, homepage);
+    }
+    header.add_str(TAG_OS, "linux");
+    header.add_str(TAG_ARCH, arch);
+    header.add_str(TAG_SOURCERPM,
+        format!("{}-{}-{}.src.rpm", meta.name, rpm_ver, release));
+    header.add_str(TAG_PAYLOADFORMAT, "cpio");
+    header.add_str(TAG_PAYLOADCOMPRESSOR, compressor);
+    header.add_str(TAG_PAYLOADFLAGS, compression.level().to_string());
+
+    for &(name, ref data) in &scripts {
+        // rpm passes the number of installed instances of the package
+        // as `$1`, which is converted into arguments of `dpkg`
+        let (body_tag, prog_tag, count, first, other) = match name {
+            "preinst" => (TAG_PREIN, TAG_PREINPROG,
+                          1, "install", "upgrade"),
+            "postinst" => (TAG_POSTIN, TAG_POSTINPROG,
+                           1, "configure", "configure"),
+            "prerm" => (TAG_PREUN, TAG_PREUNPROG, 0, "remove", "upgrade"),
+            "postrm" => (TAG_POSTUN, TAG_POSTUNPROG, 0, "remove", "upgrade"),
+            _ => unreachable!(),
+        };
+        let mut body = format!("if [ \"$1\" -eq {} ]; then\n    \
+                                    set -- {}\n\
+                                else\n    \
+                                    set -- {}\n\
+                                fi\n", count, first, other).into_bytes();
+        body.extend(script_call("\"$@\"", data));
+        header.add(body_tag,
+                   Value::Str(String::from_utf8_lossy(&body).into_owned()));
+        header.add(prog_tag, Value::StrArray(vec![String::from("/bin/sh")]));
+    }
+
+    if entries.len() > 0 {
+        let mut dirnames = Vec::<String>::new();
+        let mut dirindexes = Vec::new();
+        let mut basenames = Vec::new();
+        for e in &entries {
+            let split = e.path.rfind('/').unwrap() + 1;
+            let dirname = &e.path[..split];
+            let idx = match dirnames.iter().position(|d| d == dirname) {
+                Some(idx) => idx,
+                None => {
+                    dirnames.push(dirname.to_string());
+                    dirnames.len() - 1
+                }
+            };
+            dirindexes.push(idx as u32);
+            basenames.push(e.path[split..].to_string());
+        }
+        let num = entries.len();
+        header.add(TAG_FILESIZES,
+            Value::Int32(entries.iter().map(|e| e.size).collect()));
+        header.add(TAG_FILEMODES,
+            Value::Int16(entries.iter().map(|e| e.mode).collect()));
+        header.add(TAG_FILERDEVS,
+            Value::Int16(entries.iter().map(|e| e.rdev).collect()));
+        header.add(TAG_FILEMTIMES,
+            Value::Int32(entries.iter().map(|e| e.mtime).collect()));
+        header.add(TAG_FILEDIGESTS,
+            Value::StrArray(entries.iter().map(|e| e.digest.clone())
+                            .collect()));
+        header.add(TAG_FILELINKTOS,
+            Value::StrArray(entries.iter().map(|e| e.link.clone())
+                            .collect()));
+        header.add(TAG_FILEFLAGS,
+            Value::Int32(entries.iter().map(|e| e.flags).collect()));
+        header.add(TAG_FILEUSERNAME,
+            Value::StrArray(entries.iter().map(|e| e.user.clone())
+                            .collect()));
+        header.add(TAG_FILEGROUPNAME,
+            Value::StrArray(entries.iter().map(|e| e.group.clone())
+                            .collect()));
+        header.add(TAG_FILEVERIFYFLAGS,
+            Value::Int32(vec![u32::max_value(); num]));
+        header.add(TAG_FILEDEVICES, Value::Int32(vec![1; num]));
+        header.add(TAG_FILEINODES,
+            Value::Int32(entries.iter().map(|e| e.inode).collect()));
+        header.add(TAG_FILELANGS, Value::StrArray(vec![String::new(); num]));
+        if entries.iter().any(|e| e.capabilities.len() > 0) {
+            header.add(TAG_FILECAPS,
+                Value::StrArray(entries.iter()
+                    .map(|e| e.capabilities.clone()).collect()));
+        }
+        header.add(TAG_DIRINDEXES, Value::Int32(dirindexes));
+        header.add(TAG_BASENAMES, Value::StrArray(basenames));
+        header.add(TAG_DIRNAMES, Value::StrArray(dirnames));
+    }
+
+    let mut provides = vec![Dependency {
+        name: meta.name.clone(),
+        flags: SENSE_EQUAL,
+        version: format!("{}{}-{}",
+            epoch.map(|x| format!("{}:", x)).unwrap_or(String::new()),
+            rpm_ver, release),
+    }];
+    if let Some(ref value) = meta.provides {
+        provides.extend(try!(parse_dependencies("provides", value)));
+    }
+    add_dependencies(&mut header,
+        (TAG_PROVIDENAME, TAG_PROVIDEFLAGS, TAG_PROVIDEVERSION), provides);
+
+    let mut requires = Vec::new();
+    if let Some(ref value) = meta.pre_depends {
+        requires.extend(try!(parse_dependencies("pre-depends", value))
+            .into_iter()
+            .map(|d| Dependency { flags: d.flags | SENSE_PREREQ, .. d }));
+    }
+    if let Some(ref value) = meta.depends {
+        requires.extend(try!(parse_dependencies("depends", value)));
+    }
+    if requires.iter().any(|d| d.name.starts_with('(')) {
+        requires.push(rpmlib("RichDependencies", "4.12.0-1"));
+    }
+    requires.push(rpmlib("CompressedFileNames", "3.0.4-1"));
+    if entries.iter().any(|e| e.capabilities.len() > 0) {
+        requires.push(rpmlib("FileCaps", "4.6.1-1"));
+    }
+    requires.push(rpmlib("PayloadFilesHavePrefix", "4.0-1"));
+    if compression.kind == Compression::xz {
+        requires.push(rpmlib("PayloadIsXz", "5.2-1"));
+    } else if compression.kind == Compression::zstd {
+        requires.push(rpmlib("PayloadIsZstd", "5.4.18-1"));
+    }
+    add_dependencies(&mut header,
+        (TAG_REQUIRENAME, TAG_REQUIREFLAGS, TAG_REQUIREVERSION), requires);
+
+    let mut conflicts = Vec::new();
+    for &(name, ref value) in &[("conflicts", &meta.conflicts),
+                                ("breaks", &meta.breaks)] {
+        if let Some(ref value) = **value {
+            conflicts.extend(try!(parse_dependencies(name, value)));
+        }
+    }
+    add_dependencies(&mut header,
+        (TAG_CONFLICTNAME, TAG_CONFLICTFLAGS, TAG_CONFLICTVERSION),
+        conflicts);
+    if let Some(ref value) = meta.recommends {
+        add_dependencies(&mut header,
+            (TAG_RECOMMENDNAME, TAG_RECOMMENDFLAGS, TAG_RECOMMENDVERSION),
+            try!(parse_dependencies("recommends", value)));
+    }
+    if let Some(ref value) = meta.suggests {
+        add_dependencies(&mut header,
+            (TAG_SUGGESTNAME, TAG_SUGGESTFLAGS, TAG_SUGGESTVERSION),
+            try!(parse_dependencies("suggests", value)));
+    }
+    let header = header.to_bytes();
+
+    let payload_len = try!(payload.seek(SeekFrom::Current(0)));
+    try!(payload.seek(SeekFrom::Start(0)));
+    let mut md5 = md5::Context::new();
+    md5.consume(&header);
+    try!(io::copy(&mut payload, &mut md5));
+    try!(payload.seek(SeekFrom::Start(0)));
+    let mut sha256 = Sha256::new();
+    sha256.input(&header);
+
+    let mut signature = Header::new(HEADERSIGNATURES);
+    signature.add_str(SIG_SHA256, format!("{:x}", sha256.result()));
+    signature.add(SIG_SIZE, Value::Int32(vec![
+        (header.len() as u64 + payload_len) as u32]));
+    signature.add(SIG_MD5, Value::Bin(md5.compute().to_vec()));
+    signature.add(SIG_PAYLOADSIZE, Value::Int32(vec![payload_size as u32]));
+    let mut signature = signature.to_bytes();
+    while signature.len() % 8 != 0 {
+        signature.push(0);
+    }
+
+    let mut out = BufWriter::new(out);
+    let mut lead = Vec::with_capacity(96);
+    lead.extend(b"\xed\xab\xee\xdb\x03\x00\x00\x00");
+    let arch_num = lead_arch(arch);
+    lead.extend(&[(arch_num >> 8) as u8, arch_num as u8]);
+    let mut lead_name = format!("{}-{}-{}", meta.name, rpm_ver, release)
+        .into_bytes();
+    lead_name.resize(65, 0);
+    lead.extend(&lead_name);
+    lead.push(0);
+    lead.extend(b"\x00\x01\x00\x05");
+    lead.extend(&[0u8; 16]);
+    try!(out.write_all(&lead));
+    try!(out.write_all(&signature));
+    try!(out.write_all(&header));
+    try!(io::copy(&mut payload, &mut out));
+    try!(out.flush());
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::fs::{File, create_dir_all, hard_link};
+    use std::io::Write;
+    use std::os::unix::fs::symlink;
+    use std::str::from_utf8;
+    use tempfile::{tempdir, TempDir};
+
+    use config::{Config, Metadata, Compression};
+    use pack::compress::{self, decompress};
+    use pack::files::{self, Entry, Filter, Kind};
+    use pack::members;
+    use super::{write_rpm, HEADER_MAGIC, HEADERSIGNATURES, HEADERIMMUTABLE};
+    use super::{TAG_NAME, TAG_VERSION, TAG_SIZE, TAG_BASENAMES};
+    use super::{TAG_DIRNAMES, TAG_PREIN, TAG_POSTIN, TAG_PREINPROG};
+
+    fn metadata() -> Metadata {
+        Config::parse_string("minimum-bulk: v0.4.5\n\
+            metadata:\n  \
+              name: hello\n  \
+              short-description: hello world\n  \
+              long-description: prints hello world\n")
+            .unwrap().metadata.unwrap()
+    }
+
+    /// Collects a binary, its hard link, a symlink and a config
+    fn tree(meta: &Metadata) -> (TempDir, Vec<Entry>) {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("pkg");
+        create_dir_all(dir.join("usr/bin")).unwrap();
+        create_dir_all(dir.join("etc")).unwrap();
+        File::create(dir.join("usr/bin/hello")).unwrap()
+            .write_all(b"hello").unwrap();
+        File::create(dir.join("etc/hello.conf")).unwrap()
+            .write_all(b"greeting=hello\n").unwrap();
+        hard_link(dir.join("usr/bin/hello"), dir.join("usr/bin/hello2"))
+            .unwrap();
+        symlink("hello", dir.join("usr/bin/hi")).unwrap();
+        let options = files::Options {
+            filter: Filter::new(&[], &[]).unwrap(),
+            xattrs: false,
+            mtime: 1,
+            clamp_mtime: None,
+        };
+        let entries = files::collect(&dir, meta, &options).unwrap();
+        (tmp, entries)
+    }
+
+    fn rpm(meta: &Metadata, files: &[Entry]) -> Result<Vec<u8>, String> {
+        let tmp = tempdir().unwrap();
+        let compression = compress::Settings {
+            kind: Compression::gzip,
+            level: None,
+            jobs: 1,
+        };
+        let mut buf = Vec::new();
+        write_rpm(&mut buf, tmp.path(), files, meta, "1.2.3", "amd64",
+                  compression, 1).map_err(|e| e.to_string())?;
+        Ok(buf)
+    }
+
+    fn be32(data: &[u8], pos: usize) -> u32 {
+        data[pos..pos+4].iter().fold(0, |acc, &x| acc << 8 | x as u32)
+    }
+
+    /// Parses header at `pos`, returns (type, value) by tag and its length
+    ///
+    /// Also checks that region tag and its trailer point to each other.
+    fn parse_header(data: &[u8], pos: usize, region: u32)
+        -> (BTreeMap<u32, (u32, Vec<u8>)>, usize)
+    {
+        assert_eq!(&data[pos..pos+8], HEADER_MAGIC);
+        let count = be32(data, pos + 8) as usize;
+        let size = be32(data, pos + 12) as usize;
+        let index = pos + 16;
+        let store = &data[index + count*16..][..size];
+        assert_eq!(be32(data, index), region);
+        assert_eq!(be32(data, index + 4), 7);
+        assert_eq!(be32(data, index + 12), 16);
+        let trailer = be32(data, index + 8) as usize;
+        assert_eq!(trailer, size - 16);
+        assert_eq!(be32(store, trailer), region);
+        assert_eq!(be32(store, trailer + 4), 7);
+        assert_eq!(be32(store, trailer + 8) as i32, -(count as i32 * 16));
+        assert_eq!(be32(store, trailer + 12), 16);
+        let offsets = (1..count)
+            .map(|i| be32(data, index + i*16 + 8) as usize)
+            .collect::<Vec<_>>();
+        let mut tags = BTreeMap::new();
+        for i in 1..count {
+            let offset = offsets[i-1];
+            let end = offsets.iter().cloned()
+                .filter(|&x| x > offset)
+                .min().unwrap_or(trailer);
+            tags.insert(be32(data, index + i*16),
+                (be32(data, index + i*16 + 4), store[offset..end].to_vec()));
+        }
+        (tags, 16 + count*16 + size)
+    }
+
+    fn strings(value: &(u32, Vec<u8>)) -> Vec<&str> {
+        let data = value.1.split(|&x| x == 0).map(|x| from_utf8(x).unwrap());
+        match value.0 {
+            6 => data.take(1).collect(),
+            8 => data.filter(|x| x.len() > 0).collect(),
+            typ => panic!("type {} is not a string", typ),
+        }
+    }
+
+    /// Returns header and a position of the payload
+    fn header(buf: &[u8]) -> (BTreeMap<u32, (u32, Vec<u8>)>, usize) {
+        let (_, sig_len) = parse_header(buf, 96, HEADERSIGNATURES);
+        let header_pos = (96 + sig_len + 7) & !7;
+        let (header, header_len) = parse_header(buf, header_pos,
+                                                HEADERIMMUTABLE);
+        (header, header_pos + header_len)
+    }
+
+    #[test]
+    fn round_trip() {
+        let meta = metadata();
+        let (_dir, files) = tree(&meta);
+        let buf = rpm(&meta, &files).unwrap();
+
+        assert_eq!(&buf[..4], b"\xed\xab\xee\xdb");
+        assert!(buf[10..].starts_with(b"hello-1.2.3-1\0"));
+        let (signature, sig_len) = parse_header(&buf, 96, HEADERSIGNATURES);
+        assert!(signature.len() > 0);
+        let pos = 96 + sig_len;
+        // Padding of the signature is zeroed and is less than 8 bytes
+        let header_pos = (pos + 7) & !7;
+        assert!(buf[pos..header_pos].iter().all(|&x| x == 0));
+        assert_eq!(&buf[header_pos..header_pos+8], HEADER_MAGIC);
+
+        let (header, header_len) = parse_header(&buf, header_pos,
+                                                HEADERIMMUTABLE);
+        assert_eq!(strings(&header[&TAG_NAME]), vec!["hello"]);
+        assert_eq!(strings(&header[&TAG_VERSION]), vec!["1.2.3"]);
+        let dirs = strings(&header[&TAG_DIRNAMES]);
+        let mut names = strings(&header[&TAG_BASENAMES]);
+        names.sort();
+        assert!(dirs.contains(&"/usr/bin/"));
+        assert_eq!(names, vec!["hello", "hello.conf", "hello2", "hi"]);
+        // Hard linked data is counted once, symlink size is its length
+        assert_eq!(be32(&header[&TAG_SIZE].1, 0), 5 + 15 + 5);
+
+        let payload = decompress(&buf[header_pos + header_len..]).unwrap();
+        assert!(payload.windows(10).any(|x| x == b"TRAILER!!!"));
+        assert_eq!(payload.len() % 4, 0);
+
+        let members = members::list(&buf).unwrap();
+        assert!(members.iter().any(|m| m.name == "payload:./usr/bin/hello"));
+    }
+
+    #[test]
+    fn scripts() {
+        let mut meta = metadata();
+        meta.scripts.preinst = Some(String::from("#!/bin/sh -e\ntrue\n"));
+        meta.scripts.postinst = Some(String::from("#!/bin/bash\nldconfig"));
+        let buf = rpm(&meta, &[]).unwrap();
+        let (header, _) = header(&buf);
+        assert_eq!(strings(&header[&TAG_PREINPROG]), vec!["/bin/sh"]);
+        assert_eq!(strings(&header[&TAG_PREIN]), vec!["\
+            if [ \"$1\" -eq 1 ]; then\n    \
+                set -- install\n\
+            else\n    \
+                set -- upgrade\n\
+            fi\n\
+            /bin/sh -e /dev/stdin \"$@\" <<'__BULK_SCRIPT_END__'\n\
+            #!/bin/sh -e\ntrue\n\
+            __BULK_SCRIPT_END__\n"]);
+        assert_eq!(strings(&header[&TAG_POSTIN]), vec!["\
+            if [ \"$1\" -eq 1 ]; then\n    \
+                set -- configure\n\
+            else\n    \
+                set -- configure\n\
+            fi\n\
+            /bin/bash /dev/stdin \"$@\" <<'__BULK_SCRIPT_END__'\n\
+            #!/bin/bash\nldconfig\n\
+            __BULK_SCRIPT_END__\n"]);
+    }
+
+    #[test]
+    fn devices() {
+        let meta = metadata();
+        let small = Kind::CharDevice { major: 1, minor: 3 };
+        assert!(rpm(&meta, &[Entry::synthetic("dev/null", small)]).is_ok());
+        let large = Kind::BlockDevice { major: 259, minor: 1 };
+        assert_eq!(rpm(&meta, &[Entry::synthetic("dev/nvme0n1p1", large)]),
+                   Err(String::from("device \"dev/nvme0n1p1\" number \
+                                     is too large for rpm")));
+    }
+}
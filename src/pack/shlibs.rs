@@ -0,0 +1,306 @@
+use std::env;
+use std::io::{self, Read, Write, stderr};
+use std::fs::{File, read_dir};
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use version::Version;
+use super::elf;
+use super::files::Entry;
+
+
+/// How a library maps to a package in dpkg `symbols` file
+struct Symbols {
+    /// Dependency template, e.g. `libc6 #MINVER#`
+    template: String,
+    /// Minimal package version for each symbol version (`GLIBC_2.34`)
+    versions: HashMap<String, String>,
+}
+
+/// Libraries needed by binaries of the package
+struct Needed {
+    /// Symbol versions required from each library
+    libraries: BTreeMap<String, BTreeSet<String>>,
+    /// First binary that needs each library (for warnings)
+    users: HashMap<String, PathBuf>,
+    /// Libraries provided by the package itself
+    provided: BTreeSet<String>,
+}
+
+
+fn admin_dir() -> PathBuf {
+    env::var_os("DPKG_ADMINDIR").map(PathBuf::from)
+        .unwrap_or(PathBuf::from("/var/lib/dpkg"))
+}
+
+fn read_needed(files: &[Entry]) -> io::Result<Needed> {
+    let mut result = Needed {
+        libraries: BTreeMap::new(),
+        users: HashMap::new(),
+        provided: BTreeSet::new(),
+    };
+    for entry in files {
+        if let Some(name) = entry.name.file_name() {
+            result.provided.insert(name.to_string_lossy().into_owned());
+        }
+        let path = match entry.file_path() {
+            Some(path) => path,
+            None => continue,
+        };
+        let header = match try!(elf::read_header(path)) {
+            Some(header) => header,
+            None => continue,
+        };
+        let sections = try!(elf::read_sections(path, &header));
+        let dynamic = try!(elf::read_dynamic(path, &header, &sections));
+        if let Some(soname) = dynamic.soname {
+            result.provided.insert(soname);
+        }
+        for lib in dynamic.needed {
+            result.users.entry(lib.clone())
+                .or_insert_with(|| entry.name.clone());
+            result.libraries.entry(lib).or_insert_with(BTreeSet::new);
+        }
+        for (lib, names) in dynamic.versions {
+            result.libraries.entry(lib).or_insert_with(BTreeSet::new)
+                .extend(names);
+        }
+    }
+    Ok(result)
+}
+
+fn read_file(path: &Path) -> io::Result<String> {
+    let mut data = String::new();
+    try!(File::open(path)?.read_to_string(&mut data));
+    Ok(data)
+}
+
+/// Parses dpkg `symbols` file, only libraries in `needed` are returned
+///
+/// Only symbol versions (`GLIBC_2.34@GLIBC_2.34 2.34`) are used as we
+/// don't look at individual symbols.
+fn parse_symbols(data: &str, needed: &Needed,
+    out: &mut HashMap<String, Symbols>)
+{
+    let mut current = None;
+    for line in data.lines() {
+        if line.starts_with(' ') {
+            let lib = match current {
+                Some(ref lib) => lib,
+                None => continue,
+            };
+            let mut words = line.split_whitespace();
+            if let (Some(symbol), Some(version)) = (words.next(), words.next())
+            {
+                let mut parts = symbol.splitn(2, '@');
+                let name = parts.next().unwrap();
+                if parts.next() == Some(name) {
+                    out.get_mut(lib).unwrap().versions
+                        .insert(name.to_string(), version.to_string());
+                }
+            }
+        } else if line.starts_with('|') || line.starts_with('*') ||
+            line.starts_with('#')
+        {
+            // Alternative dependencies and meta-information aren't used
+            continue;
+        } else {
+            current = None;
+            let mut parts = line.trim().splitn(2, ' ');
+            if let (Some(lib), Some(template)) = (parts.next(), parts.next())
+            {
+                if needed.libraries.contains_key(lib) &&
+                    !out.contains_key(lib)
+                {
+                    out.insert(lib.to_string(), Symbols {
+                        template: template.trim().to_string(),
+                        versions: HashMap::new(),
+                    });
+                    current = Some(lib.to_string());
+                }
+            }
+        }
+    }
+}
+
+/// Parses dpkg `shlibs` file (`libfoo 1 libfoo1 (>= 1.2)` lines)
+fn parse_shlibs(data: &str, out: &mut HashMap<String, String>) {
+    for line in data.lines() {
+        let line = line.trim();
+        // Lines for udebs have a type prefix (`udeb: libfoo 1 ...`)
+        if line.starts_with('#') || line.contains(": ") {
+            continue;
+        }
+        let mut words = line.splitn(3, ' ');
+        if let (Some(lib), Some(ver), Some(dep)) =
+            (words.next(), words.next(), words.next())
+        {
+            // Both `libfoo.so.1` and `libfoo-1.so` sonames are possible
+            for soname in &[format!("{}.so.{}", lib, ver),
+                            format!("{}-{}.so", lib, ver)]
+            {
+                if !out.contains_key(soname) {
+                    out.insert(soname.clone(), dep.trim().to_string());
+                }
+            }
+        }
+    }
+}
+
+/// Splits simple dependency `name (>= version)` into name and version
+fn split_dependency(dep: &str) -> Option<(&str, Option<&str>)> {
+    let dep = dep.trim();
+    if dep.contains(|c| c == ',' || c == '|') {
+        return None;
+    }
+    match dep.find('(') {
+        None => Some((dep, None)),
+        Some(pos) => {
+            let version = dep[pos+1..].trim_end_matches(')').trim();
+            if version.starts_with(">=") {
+                Some((dep[..pos].trim(), Some(version[2..].trim())))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Finds dependencies for shared libraries that binaries in the package
+/// are linked with
+///
+/// Libraries are looked up in `table` first, then in `symbols` and
+/// `shlibs` files of installed packages in dpkg database. Dependencies on
+/// the same package are merged by using the highest version.
+pub fn auto_depends(files: &[Entry], table: &BTreeMap<String, String>)
+    -> io::Result<Vec<String>>
+{
+    let needed = try!(read_needed(files));
+    let mut symbols = HashMap::new();
+    let mut shlibs = HashMap::new();
+    let info = admin_dir().join("info");
+    if needed.libraries.len() > 0 && info.exists() {
+        let mut paths = try!(read_dir(&info)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>());
+        paths.sort();
+        for path in paths {
+            match path.extension().and_then(|x| x.to_str()) {
+                Some("symbols") => {
+                    parse_symbols(&try!(read_file(&path)), &needed,
+                                  &mut symbols);
+                }
+                Some("shlibs") => {
+                    parse_shlibs(&try!(read_file(&path)), &mut shlibs);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut deps = Vec::new();
+    for (lib, versions) in &needed.libraries {
+        if needed.provided.contains(lib) {
+            continue;
+        }
+        if let Some(dep) = table.get(lib) {
+            deps.push(dep.clone());
+            continue;
+        }
+        let minver = symbols.get(lib).and_then(|sym| {
+            versions.iter().filter_map(|v| sym.versions.get(v))
+                .max_by(|a, b| Version(&a[..]).cmp(&Version(&b[..])))
+        });
+        match (symbols.get(lib), minver, shlibs.get(lib)) {
+            (Some(sym), Some(ver), _) => {
+                deps.push(sym.template
+                    .replace("#MINVER#", &format!("(>= {})", ver)));
+            }
+            (_, _, Some(dep)) => deps.push(dep.clone()),
+            (Some(sym), None, None) => {
+                deps.push(sym.template.replace("#MINVER#", "")
+                    .trim().to_string());
+            }
+            (None, _, None) => {
+                writeln!(&mut stderr(), "Warning: no package found for \
+                    library {} needed by /{}, add it to `shlibs` in config",
+                    lib, needed.users.get(lib)
+                        .map(|x| x.display().to_string())
+                        .unwrap_or_default()).ok();
+            }
+        }
+    }
+
+    // Merge dependencies on the same package
+    let mut result: Vec<String> = Vec::new();
+    for dep in deps {
+        let merged = split_dependency(&dep).and_then(|(name, ver)| {
+            result.iter().position(|d| {
+                split_dependency(d).map(|(n, _)| n) == Some(name)
+            }).map(|idx| (idx, ver))
+        });
+        match merged {
+            Some((idx, Some(ver))) => {
+                let old = split_dependency(&result[idx]).and_then(|x| x.1)
+                    .map(|x| x.to_string());
+                if old.as_ref().map(|old| Version(ver) > Version(&old[..]))
+                    .unwrap_or(true)
+                {
+                    result[idx] = dep.clone();
+                }
+            }
+            Some((_, None)) => {}
+            None if !result.contains(&dep) => result.push(dep),
+            None => {}
+        }
+    }
+    Ok(result)
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, BTreeSet, HashMap};
+    use super::{Needed, parse_symbols, parse_shlibs, split_dependency};
+
+    #[test]
+    fn symbols() {
+        let mut needed = Needed {
+            libraries: BTreeMap::new(),
+            users: HashMap::new(),
+            provided: BTreeSet::new(),
+        };
+        needed.libraries.insert("libc.so.6".into(), BTreeSet::new());
+        let mut out = HashMap::new();
+        parse_symbols("libc.so.6 libc6 #MINVER#\n\
+            | libc6 (>> 2.36), libc6 (<< 2.37)\n\
+            * Build-Depends-Package: libc-dev\n \
+            GLIBC_2.2.5@GLIBC_2.2.5 2.2.5\n \
+            GLIBC_2.34@GLIBC_2.34 2.34\n \
+            malloc@GLIBC_2.2.5 2.2.5\n\
+            libm.so.6 libc6 #MINVER#\n \
+            GLIBC_2.29@GLIBC_2.29 2.29\n", &needed, &mut out);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out["libc.so.6"].template, "libc6 #MINVER#");
+        assert_eq!(out["libc.so.6"].versions.len(), 2);
+        assert_eq!(out["libc.so.6"].versions["GLIBC_2.34"], "2.34");
+    }
+
+    #[test]
+    fn shlibs() {
+        let mut out = HashMap::new();
+        parse_shlibs("libssl 3 libssl3t64 (>= 3.0.0)\n\
+                      udeb: libssl 3 libssl3-udeb\n", &mut out);
+        assert_eq!(out["libssl.so.3"], "libssl3t64 (>= 3.0.0)");
+        assert_eq!(out["libssl-3.so"], "libssl3t64 (>= 3.0.0)");
+    }
+
+    #[test]
+    fn dependency() {
+        assert_eq!(split_dependency("libc6 (>= 2.34)"),
+                   Some(("libc6", Some("2.34"))));
+        assert_eq!(split_dependency("libc6"), Some(("libc6", None)));
+        assert_eq!(split_dependency("libc6 (<< 3)"), None);
+        assert_eq!(split_dependency("a | b"), None);
+    }
+}
@@ -1,69 +1,209 @@
-use std::io;
-use std::fs::{File, symlink_metadata, read_link};
+use std::io::{self, Read};
+use std::fs::File;
 use std::path::Path;
-use std::os::unix::fs::PermissionsExt;
 
+use md5;
+use sha2::{Sha256, Digest};
 use tar;
 
+use hash_file::Md5Reader;
+use super::files::{Entry, Kind};
+
+
+/// Size and checksum of a regular file added to the archive
+pub struct Checksum {
+    pub size: u64,
+    pub md5: md5::Digest,
+}
+
+
+/// Reader that also feeds the data into a digest (if there is one)
+struct DigestReader<'a, R: Read, D: Digest + 'a> {
+    inner: R,
+    digest: Option<&'a mut D>,
+}
+
+impl<'a, R: Read, D: Digest> Read for DigestReader<'a, R, D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = try!(self.inner.read(buf));
+        if let Some(ref mut digest) = self.digest {
+            digest.input(&buf[..bytes]);
+        }
+        Ok(bytes)
+    }
+}
+
+/// Formats PAX extended header records for extended attributes
+fn pax_xattrs(entry: &Entry) -> Vec<u8> {
+    let mut result = Vec::new();
+    for (name, value) in &entry.xattrs {
+        // Length of the record includes the length field itself
+        let len = " SCHILY.xattr.=\n".len() + name.len() + value.len();
+        let mut total = len + 1;
+        while total != len + total.to_string().len() {
+            total = len + total.to_string().len();
+        }
+        result.extend(format!("{} SCHILY.xattr.{}=", total, name).bytes());
+        result.extend(value);
+        result.push(b'\n');
+    }
+    return result;
+}
 
 pub trait ArchiveExt {
-    fn append_blob<P: AsRef<Path>>(&mut self, name: P, mtime: u32, data: &[u8])
+    fn append_blob<P: AsRef<Path>>(&mut self, name: P, mtime: u32, mode: u32,
+        data: &[u8])
         -> Result<(), io::Error>;
-    fn append_file_at<P: AsRef<Path>, Q: AsRef<Path>>(&mut self,
-        dir: P, path: Q, mtime: u32)
+    fn append_empty_dir<P: AsRef<Path>>(&mut self, name: P, mtime: u32,
+        mode: u32)
         -> Result<(), io::Error>;
+    fn append_entry(&mut self, entry: &Entry)
+        -> Result<Option<Checksum>, io::Error>;
+    fn append_entry_under(&mut self, entry: &Entry, prefix: &Path)
+        -> Result<Option<Checksum>, io::Error>;
+    fn append_entry_digest<D: Digest>(&mut self, entry: &Entry,
+        digest: &mut D)
+        -> Result<Option<Checksum>, io::Error>;
 }
 
 impl<T: io::Write> ArchiveExt for tar::Builder<T> {
-    fn append_blob<P: AsRef<Path>>(&mut self, name: P, mtime: u32, data: &[u8])
+    fn append_blob<P: AsRef<Path>>(&mut self, name: P, mtime: u32, mode: u32,
+        data: &[u8])
         -> Result<(), io::Error>
     {
         let mut head = tar::Header::new_gnu();
         head.set_mtime(mtime as u64);
         head.set_size(data.len() as u64);
-        head.set_mode(0o644);
+        head.set_mode(mode);
         head.set_cksum();
         self.append_data(&mut head, name, &mut io::Cursor::new(&data))
     }
-    /// This does same as Builder::append_file, but has no mtime/size/owner
-    /// information which we explicitly have chosen to omit
-    ///
-    /// Silently skips things that are neither files nor symlinks
-    fn append_file_at<P: AsRef<Path>, Q: AsRef<Path>>(&mut self,
-        dir: P, path: Q, mtime: u32)
+    fn append_empty_dir<P: AsRef<Path>>(&mut self, name: P, mtime: u32,
+        mode: u32)
         -> Result<(), io::Error>
     {
-        let path = path.as_ref();
-        let fullpath = dir.as_ref().join(path);
-        let meta = try!(symlink_metadata(&fullpath));
-
         let mut head = tar::Header::new_gnu();
+        head.set_entry_type(tar::EntryType::Directory);
         head.set_mtime(mtime as u64);
+        head.set_size(0);
+        head.set_mode(mode);
+        head.set_cksum();
+        self.append_data(&mut head, name, &mut io::empty())
+    }
+    /// This does same as Builder::append_file, but has no mtime/size
+    /// information which we explicitly have chosen to omit, and owner
+    /// and mode are taken from the entry
+    ///
+    /// Returns checksum for regular files, the file is read only once
+    fn append_entry(&mut self, entry: &Entry)
+        -> Result<Option<Checksum>, io::Error>
+    {
+        self.append_entry_under(entry, Path::new(""))
+    }
+    /// Same as `append_entry` but stores file (and hard link target)
+    /// inside the `prefix` directory
+    fn append_entry_under(&mut self, entry: &Entry, prefix: &Path)
+        -> Result<Option<Checksum>, io::Error>
+    {
+        append_entry::<_, Sha256>(self, entry, prefix, None)
+    }
+    /// Same as `append_entry` but also feeds data of regular files into
+    /// `digest`
+    fn append_entry_digest<D: Digest>(&mut self, entry: &Entry,
+        digest: &mut D)
+        -> Result<Option<Checksum>, io::Error>
+    {
+        append_entry(self, entry, Path::new(""), Some(digest))
+    }
+}
 
-        if meta.file_type().is_file() {
+fn append_entry<T, D>(arch: &mut tar::Builder<T>, entry: &Entry,
+    prefix: &Path, digest: Option<&mut D>)
+    -> Result<Option<Checksum>, io::Error>
+    where T: io::Write, D: Digest,
+{
+    let name = prefix.join(&entry.name);
+    if entry.xattrs.len() > 0 {
+        let records = pax_xattrs(entry);
+        let mut head = tar::Header::new_ustar();
+        head.set_entry_type(tar::EntryType::XHeader);
+        head.set_mtime(entry.mtime as u64);
+        head.set_size(records.len() as u64);
+        head.set_mode(0o644);
+        let pax_name = Path::new("PaxHeaders")
+            .join(entry.name.file_name().unwrap_or("".as_ref()));
+        try!(arch.append_data(&mut head, pax_name, &records[..]));
+    }
+    let mut head = tar::Header::new_gnu();
+    head.set_mtime(entry.mtime as u64);
+    head.set_uid(entry.attrs.uid);
+    head.set_gid(entry.attrs.gid);
+    if let Some(ref user) = entry.attrs.user {
+        try!(head.set_username(user));
+    }
+    if let Some(ref group) = entry.attrs.group {
+        try!(head.set_groupname(group));
+    }
+    head.set_mode(entry.mode);
+
+    match entry.kind {
+        Kind::File { size } => {
+            let path = entry.source.as_ref().expect("file has source");
             head.set_entry_type(tar::EntryType::Regular);
-            let mut file = try!(File::open(&fullpath));
-            head.set_size(meta.len() as u64);
-            head.set_mode(meta.permissions().mode());
+            let mut file = Md5Reader::new(DigestReader {
+                inner: try!(File::open(path)).take(size),
+                digest: digest,
+            });
+            head.set_size(size);
             head.set_cksum();
-            self.append_data(&mut head, &path, &mut file)
-        } else if meta.file_type().is_symlink() {
+            try!(arch.append_data(&mut head, name, &mut file));
+            Ok(Some(Checksum {
+                size: size,
+                md5: file.digest(),
+            }))
+        }
+        Kind::Symlink(ref lnk) => {
             head.set_entry_type(tar::EntryType::Symlink);
-            let lnk = try!(read_link(&fullpath));
             head.set_size(0);
-            head.set_mode(meta.permissions().mode());
             try!(head.set_link_name(lnk));
             head.set_cksum();
-            self.append_data(&mut head, &path, &mut io::empty())
-        } else if meta.file_type().is_dir() {
+            try!(arch.append_data(&mut head, name, &mut io::empty()));
+            Ok(None)
+        }
+        Kind::HardLink { ref target, .. } => {
+            head.set_entry_type(tar::EntryType::Link);
+            head.set_size(0);
+            try!(head.set_link_name(prefix.join(target)));
+            head.set_cksum();
+            try!(arch.append_data(&mut head, name, &mut io::empty()));
+            Ok(None)
+        }
+        Kind::Dir => {
             head.set_entry_type(tar::EntryType::Directory);
             head.set_size(0);
-            head.set_mode(meta.permissions().mode());
             head.set_cksum();
-            self.append_data(&mut head, &path, &mut io::empty())
-        } else {
-            // Silently skip as documented
-            Ok(())
+            try!(arch.append_data(&mut head, name, &mut io::empty()));
+            Ok(None)
+        }
+        Kind::Fifo => {
+            head.set_entry_type(tar::EntryType::Fifo);
+            head.set_size(0);
+            head.set_cksum();
+            try!(arch.append_data(&mut head, name, &mut io::empty()));
+            Ok(None)
+        }
+        Kind::CharDevice { major, minor } |
+        Kind::BlockDevice { major, minor } => {
+            head.set_entry_type(match entry.kind {
+                Kind::CharDevice { .. } => tar::EntryType::Char,
+                _ => tar::EntryType::Block,
+            });
+            head.set_size(0);
+            try!(head.set_device_major(major));
+            try!(head.set_device_minor(minor));
+            head.set_cksum();
+            try!(arch.append_data(&mut head, name, &mut io::empty()));
+            Ok(None)
         }
     }
 }
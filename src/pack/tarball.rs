@@ -0,0 +1,123 @@
+use std::io::{self, Write, BufWriter};
+use std::path::{Path, Component};
+
+use tar::{Builder as Archive};
+
+use config::Metadata;
+use super::compress;
+use super::files::Entry;
+use super::tar::ArchiveExt;
+
+
+/// Returns file name of the tarball (without directory)
+pub fn package_name(meta: &Metadata, version: &str,
+    compression: compress::Settings)
+    -> String
+{
+    format!("{}-{}.tar{}", meta.name, version, compression.extension())
+}
+
+/// Writes package directory as a plain tarball
+///
+/// All files are put into `prefix` directory if it's specified
+pub fn write_tarball<W: Write>(out: W, files: &[Entry],
+    prefix: Option<&Path>,
+    compression: compress::Settings, mtime: u32)
+    -> Result<(), io::Error>
+{
+    if let Some(prefix) = prefix {
+        if prefix.is_absolute() ||
+            prefix.components().any(|c| c == Component::ParentDir)
+        {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("tarball prefix {:?} must be a relative path \
+                         without `..`", prefix)));
+        }
+    }
+    let mut out = compression.encoder(BufWriter::new(out))?;
+    {
+        let mut arch = Archive::new(&mut out);
+        if let Some(prefix) = prefix {
+            arch.append_empty_dir(prefix, mtime, 0o755)?;
+        }
+        for entry in files {
+            arch.append_entry_under(entry, prefix.unwrap_or(Path::new("")))?;
+        }
+        arch.finish()?;
+    }
+    out.finish()?.flush()?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::fs::{File, create_dir_all, hard_link};
+    use std::path::Path;
+    use tar;
+    use tempfile::tempdir;
+
+    use config::{Config, Compression};
+    use pack::compress;
+    use pack::files::{self, Filter};
+    use super::write_tarball;
+
+    fn tarball(prefix: &str) -> Result<Vec<u8>, String> {
+        let meta = Config::parse_string("minimum-bulk: v0.4.5\n\
+            metadata:\n  \
+              name: hello\n  \
+              short-description: hello world\n  \
+              long-description: prints hello world\n")
+            .unwrap().metadata.unwrap();
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("pkg");
+        create_dir_all(dir.join("usr/bin")).unwrap();
+        File::create(dir.join("usr/bin/hello")).unwrap();
+        hard_link(dir.join("usr/bin/hello"), dir.join("usr/bin/hello2"))
+            .unwrap();
+        let settings = compress::Settings {
+            kind: Compression::none,
+            level: None,
+            jobs: 1,
+        };
+        let options = files::Options {
+            filter: Filter::new(&[], &[]).unwrap(),
+            xattrs: false,
+            mtime: 1,
+            clamp_mtime: None,
+        };
+        let files = files::collect(&dir, &meta, &options).unwrap();
+        let mut buf = Vec::new();
+        write_tarball(&mut buf, &files, Some(Path::new(prefix)),
+                      settings, 1)
+            .map_err(|e| e.to_string())?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn prefix() {
+        let data = tarball("hello-1.0").unwrap();
+        let mut arch = tar::Archive::new(&data[..]);
+        let mut links = 0;
+        for entry in arch.entries().unwrap() {
+            let entry = entry.unwrap();
+            assert!(entry.path().unwrap().starts_with("hello-1.0"));
+            // Hard link target is inside the prefix too
+            if entry.header().entry_type() == tar::EntryType::Link {
+                assert_eq!(entry.link_name().unwrap().unwrap(),
+                           Path::new("hello-1.0/usr/bin/hello"));
+                links += 1;
+            }
+        }
+        assert_eq!(links, 1);
+    }
+
+    #[test]
+    fn bad_prefix() {
+        for prefix in &["/opt/hello", "..", "hello/../../etc"] {
+            let err = tarball(prefix).unwrap_err();
+            assert!(err.contains("must be a relative path without `..`"),
+                    "{}: {}", prefix, err);
+        }
+    }
+}
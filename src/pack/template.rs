@@ -0,0 +1,169 @@
+use std::env;
+
+use git2::Repository;
+
+use config::Metadata;
+
+
+/// Values of variables that can be used in metadata fields
+pub struct Vars<'a> {
+    name: &'a str,
+    version: &'a str,
+    arch: &'a str,
+    /// Commit is only looked up when `${git.commit}` is used, so packages
+    /// can be built outside of a git repository
+    commit: Option<String>,
+}
+
+impl<'a> Vars<'a> {
+    pub fn new(name: &'a str, version: &'a str, arch: &'a str) -> Vars<'a> {
+        Vars {
+            name: name,
+            version: version,
+            arch: arch,
+            commit: None,
+        }
+    }
+    fn git_commit(&mut self) -> Result<String, String> {
+        if self.commit.is_none() {
+            let repo = Repository::discover(".")
+                .map_err(|e| format!("can't open git repository: {}", e))?;
+            let commit = repo.head()
+                .and_then(|head| head.peel_to_commit())
+                .map_err(|e| format!("can't find git commit: {}", e))?;
+            self.commit = Some(commit.id().to_string());
+        }
+        Ok(self.commit.clone().unwrap())
+    }
+    fn get(&mut self, var: &str) -> Result<String, String> {
+        match var {
+            "version" => Ok(self.version.to_string()),
+            "name" => Ok(self.name.to_string()),
+            "arch" => Ok(self.arch.to_string()),
+            "git.commit" => self.git_commit(),
+            _ if var.starts_with("env.") => {
+                env::var(&var[4..]).map_err(|_| {
+                    format!("environment variable {:?} is not set",
+                            &var[4..])
+                })
+            }
+            _ => Err(format!("unknown variable `${{{}}}`, supported ones \
+                are `${{version}}`, `${{name}}`, `${{arch}}`, \
+                `${{git.commit}}` and `${{env.VAR}}`", var)),
+        }
+    }
+}
+
+/// Substitutes `${var}` references in `text`, `$$` is a literal dollar
+pub fn expand(text: &str, vars: &mut Vars) -> Result<String, String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find('$') {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos+1..];
+        if rest.starts_with('$') {
+            result.push('$');
+            rest = &rest[1..];
+        } else if rest.starts_with('{') {
+            let end = rest.find('}')
+                .ok_or_else(|| format!("unclosed `${{` in {:?}", text))?;
+            result.push_str(&vars.get(&rest[1..end])?);
+            rest = &rest[end+1..];
+        } else {
+            result.push('$');
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+fn expand_field(value: &mut String, field: &str, vars: &mut Vars)
+    -> Result<(), String>
+{
+    *value = expand(value, vars)
+        .map_err(|e| format!("Error in `{}` of {}: {}",
+                             field, vars.name, e))?;
+    Ok(())
+}
+
+/// Expands variables in text fields of the metadata
+///
+/// Maintainer scripts are not expanded as they are usually shell scripts
+/// which have their own `${var}` syntax.
+pub fn expand_metadata(meta: &Metadata, vars: &mut Vars)
+    -> Result<Metadata, String>
+{
+    let mut meta = meta.clone();
+    expand_field(&mut meta.short_description, "short-description", vars)?;
+    expand_field(&mut meta.long_description, "long-description", vars)?;
+    {
+        let fields = vec![
+            ("depends", &mut meta.depends),
+            ("pre-depends", &mut meta.pre_depends),
+            ("recommends", &mut meta.recommends),
+            ("suggests", &mut meta.suggests),
+            ("conflicts", &mut meta.conflicts),
+            ("breaks", &mut meta.breaks),
+            ("replaces", &mut meta.replaces),
+            ("provides", &mut meta.provides),
+            ("maintainer", &mut meta.maintainer),
+            ("section", &mut meta.section),
+            ("homepage", &mut meta.homepage),
+        ];
+        for (field, value) in fields {
+            if let Some(ref mut value) = *value {
+                expand_field(value, field, vars)?;
+            }
+        }
+    }
+    for (field, value) in meta.extra_fields.iter_mut() {
+        expand_field(value, field, vars)?;
+    }
+    Ok(meta)
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use super::{Vars, expand};
+
+    fn vars() -> Vars<'static> {
+        let mut vars = Vars::new("foo", "1.2-3", "amd64");
+        vars.commit = Some(String::from("0123abc"));
+        vars
+    }
+
+    #[test]
+    fn variables() {
+        let mut vars = vars();
+        assert_eq!(expand("foo-common (= ${version})", &mut vars).unwrap(),
+                   "foo-common (= 1.2-3)");
+        assert_eq!(expand("${name}_${arch}", &mut vars).unwrap(),
+                   "foo_amd64");
+        assert_eq!(expand("Built from ${git.commit}.", &mut vars).unwrap(),
+                   "Built from 0123abc.");
+        env::set_var("BULK_TEMPLATE_TEST", "ci-1");
+        assert_eq!(expand("${env.BULK_TEMPLATE_TEST}", &mut vars).unwrap(),
+                   "ci-1");
+    }
+
+    #[test]
+    fn dollars() {
+        let mut vars = vars();
+        assert_eq!(expand("costs $5", &mut vars).unwrap(), "costs $5");
+        assert_eq!(expand("$${version}", &mut vars).unwrap(), "${version}");
+        assert_eq!(expand("end$", &mut vars).unwrap(), "end$");
+    }
+
+    #[test]
+    fn errors() {
+        let mut vars = vars();
+        assert!(expand("${versoin}", &mut vars).unwrap_err()
+                .starts_with("unknown variable `${versoin}`"));
+        assert!(expand("${version", &mut vars).unwrap_err()
+                .starts_with("unclosed"));
+        assert!(expand("${env.BULK_TEMPLATE_UNSET}", &mut vars).unwrap_err()
+                .contains("BULK_TEMPLATE_UNSET"));
+    }
+}
@@ -3,61 +3,166 @@ use std::str;
 use std::path::Path;
 use std::os::unix::ffi::OsStrExt;
 
-pub struct Archive<T:Read>(T, bool);
+pub struct Archive<T:Read> {
+    stream: Counter<T>,
+    /// Offset of the next member header in the stream
+    next: u64,
+}
+
+/// Wraps archive stream to know how much of a member was read, so the rest
+/// of it can be skipped when reading the next member
+pub struct Counter<T:Read>(T, u64);
+
+/// Header of the archive member
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub name: String,
+    pub mtime: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+    pub size: u64,
+}
 
 
 fn error(text: &'static str) -> io::Error {
     return io::Error::new(io::ErrorKind::Other, text);
 }
 
+impl<T:Read> Read for Counter<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = try!(self.0.read(buf));
+        self.1 += bytes as u64;
+        Ok(bytes)
+    }
+}
+
+/// Parses numeric field of the header, empty fields are zero
+fn field(head: &[u8], start: usize, end: usize, radix: u32) -> io::Result<u64>
+{
+    str::from_utf8(&head[start..end]).ok()
+        .map(|x| x.trim())
+        .and_then(|x| if x.len() == 0 { Some(0) }
+                      else { u64::from_str_radix(x, radix).ok() })
+        .ok_or_else(|| error("Invalid archive member header"))
+}
+
 
 impl<T:Read> Archive<T> {
-    pub fn new(mut stream: T) -> io::Result<Archive<T>> {
+    pub fn new(stream: T) -> io::Result<Archive<T>> {
+        let mut stream = Counter(stream, 0);
         let mut sig = [0u8; 8];
-        try!(stream.read(&mut sig));
+        try!(stream.read_exact(&mut sig));
         if &sig != b"!<arch>\n" {
             return Err(error("Archive signature is wrong"));
         }
-        Ok(Archive(stream, false))
+        Ok(Archive { stream: stream, next: 8 })
+    }
+    /// Reads header of the next member, returns `None` at the end of
+    /// archive
+    ///
+    /// Unread data of the previous member is skipped.
+    pub fn next_member(&mut self)
+        -> io::Result<Option<(Header, Take<&mut Counter<T>>)>>
+    {
+        let skip = self.next - self.stream.1;
+        if try!(io::copy(&mut (&mut self.stream).take(skip),
+                         &mut io::sink())) != skip
+        {
+            return Err(error("Premature end of file"));
+        }
+        let mut buf = [0u8; 60];
+        let mut len = 0;
+        while len < buf.len() {
+            match try!(self.stream.read(&mut buf[len..])) {
+                0 if len == 0 => return Ok(None),
+                0 => return Err(error("Premature end of file")),
+                x => len += x,
+            }
+        }
+        if &buf[58..60] != b"`\n" {
+            return Err(error("Invalid file format"));
+        }
+        let fnameend = buf[..16].iter().position(|&x| x == b' ')
+                        .unwrap_or(16);
+        // GNU ar terminates names with a slash
+        let name = &buf[..fnameend];
+        let name = if name.ends_with(b"/") && name.len() > 1 {
+            &name[..name.len()-1]
+        } else {
+            name
+        };
+        let header = Header {
+            name: try!(str::from_utf8(name)
+                .map_err(|_| error("Invalid file name"))).to_string(),
+            mtime: try!(field(&buf, 16, 28, 10)),
+            uid: try!(field(&buf, 28, 34, 10)) as u32,
+            gid: try!(field(&buf, 34, 40, 10)) as u32,
+            mode: try!(field(&buf, 40, 48, 8)) as u32,
+            size: try!(field(&buf, 48, 58, 10)),
+        };
+        // Members are aligned to even offsets
+        self.next = self.stream.1 + header.size + header.size % 2;
+        let size = header.size;
+        Ok(Some((header, (&mut self.stream).take(size))))
     }
     /// Reads file with known name
     ///
     /// Since we only read debian archives, it's good enough
-    pub fn read_file<'x, P: AsRef<Path>>(&mut self, name: P)
-        -> io::Result<Take<&mut T>>
+    pub fn read_file<P: AsRef<Path>>(&mut self, name: P)
+        -> io::Result<Take<&mut Counter<T>>>
     {
         return self._read_file(name.as_ref());
     }
-    fn _read_file<'x>(&mut self, name: &Path) -> io::Result<Take<&mut T>> {
-        let mut buf = [0u8; 61];
-        let head = {
-            if self.1 {
-                let bytes = try!(self.0.read(&mut buf[..61]));
-                if bytes != 61 {
-                    return Err(error("Premature end of file"));
-                }
-                &buf[1..60]
-            } else {
-                let bytes = try!(self.0.read(&mut buf[..60]));
-                if bytes != 60 {
-                    return Err(error("Premature end of file"));
-                }
-                &buf[0..60]
-            }
-        };
-        if &head[58..60] != b"`\n" {
-            return Err(error("Invalid file format"));
+    fn _read_file(&mut self, name: &Path)
+        -> io::Result<Take<&mut Counter<T>>>
+    {
+        match try!(self.next_member()) {
+            Some((ref head, _))
+            if head.name.as_bytes() != name.as_os_str().as_bytes()
+            => Err(error("Unexpected archive member")),
+            Some((_, data)) => Ok(data),
+            None => Err(error("Premature end of file")),
         }
-        let fnameend = head[..16].iter().position(|&x| x == b' ')
-                        .unwrap_or(16);
-        if &head[..fnameend] != name.as_os_str().as_bytes() {
-            return Err(error("Unexpected archive member"));
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use super::Archive;
+
+    fn member(name: &str, data: &str) -> String {
+        let mut result = format!("{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+            name, 1, 0, 0, 100644, data.len());
+        result.push_str(data);
+        if data.len() % 2 == 1 {
+            result.push('\n');
         }
-        let size = try!(
-            str::from_utf8(&head[48..58]).ok()
-            .and_then(|x| x.trim().parse().ok())
-            .ok_or_else(|| error("Invalid file size")));
-        self.1 = size % 1 == 1;
-        Ok((&mut self.0).take(size))
+        result
+    }
+
+    #[test]
+    fn odd_sizes() {
+        let data = format!("!<arch>\n{}{}{}",
+            member("debian-binary", "2.0\n"),
+            member("odd/", "abc"),
+            member("last", "def"));
+        let mut arch = Archive::new(data.as_bytes()).unwrap();
+        let mut buf = String::new();
+        arch.read_file("debian-binary").unwrap()
+            .read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "2.0\n");
+        let (head, _) = arch.next_member().unwrap().unwrap();
+        assert_eq!(head.name, "odd");
+        assert_eq!(head.size, 3);
+        assert_eq!(head.mode, 0o100644);
+        let (head, mut body) = arch.next_member().unwrap().unwrap();
+        assert_eq!(head.name, "last");
+        buf.clear();
+        body.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "def");
+        assert!(arch.next_member().unwrap().is_none());
     }
 }
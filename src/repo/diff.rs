@@ -0,0 +1,293 @@
+use std::io::{stdout, stderr, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::collections::HashMap;
+
+use argparse::{ArgumentParser, Parse};
+use failure::Error;
+
+use repo::inspect::{read_package, Package, Member, FileEntry};
+
+
+/// Differences between two packages, each section is a list of lines
+#[derive(Debug, Default)]
+struct Diff {
+    control: Vec<String>,
+    members: Vec<String>,
+    files: Vec<String>,
+}
+
+
+fn opt<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map(|x| x.to_string()).unwrap_or(String::from("none"))
+}
+
+/// Fields of the file that are compared, mtime is not compared as it's
+/// usually different between builds
+fn file_fields(file: &FileEntry) -> Vec<(&'static str, String)> {
+    vec![
+        ("type", file.kind.to_string()),
+        ("mode", file.mode.clone()),
+        ("owner", format!("{}/{}", file.user, file.group)),
+        ("ids", format!("{}/{}", file.uid, file.gid)),
+        ("size", opt(&file.size)),
+        ("device", opt(&file.device)),
+        ("link", opt(&file.link)),
+        ("md5", opt(&file.md5)),
+    ]
+}
+
+/// Fields of the archive member that are compared
+fn member_fields(member: &Member) -> Vec<(&'static str, String)> {
+    vec![
+        ("size", member.size.to_string()),
+        ("compression", opt(&member.compression)),
+    ]
+}
+
+/// Returns name of the member without compression extension, so that
+/// `data.tar.gz` and `data.tar.xz` are compared to each other
+fn member_key(member: &Member) -> &str {
+    match (&member.compression, member.name.find(".tar")) {
+        (&Some(_), Some(pos)) => &member.name[..pos + 4],
+        _ => &member.name,
+    }
+}
+
+fn changes(old: &[(&'static str, String)], new: &[(&'static str, String)])
+    -> Vec<String>
+{
+    old.iter().zip(new)
+        .filter(|&(a, b)| a != b)
+        .map(|(&(key, ref a), &(_, ref b))| format!("{} {} -> {}", key, a, b))
+        .collect()
+}
+
+fn diff_packages(old: &Package, new: &Package) -> Diff {
+    let mut result = Diff::default();
+    for (key, value) in &old.control {
+        match new.control.get(key) {
+            Some(new_value) if new_value != value => {
+                result.control.push(format!("~ {}: {:?} -> {:?}",
+                                            key, value, new_value));
+            }
+            Some(_) => {}
+            None => result.control.push(format!("- {}: {:?}", key, value)),
+        }
+    }
+    for (key, value) in &new.control {
+        if !old.control.contains_key(key) {
+            result.control.push(format!("+ {}: {:?}", key, value));
+        }
+    }
+
+    let old_members = old.members.iter()
+        .map(|m| (member_key(m), m)).collect::<HashMap<_, _>>();
+    let new_members = new.members.iter()
+        .map(|m| (member_key(m), m)).collect::<HashMap<_, _>>();
+    for member in &old.members {
+        match new_members.get(member_key(member)) {
+            Some(new_member) => {
+                let lines = changes(&member_fields(member),
+                                    &member_fields(new_member));
+                if lines.len() > 0 {
+                    result.members.push(format!("~ {}: {}",
+                        member.name, lines.join(", ")));
+                }
+            }
+            None => result.members.push(format!("- {}", member.name)),
+        }
+    }
+    for member in &new.members {
+        if !old_members.contains_key(member_key(member)) {
+            result.members.push(format!("+ {}", member.name));
+        }
+    }
+
+    let old_files = old.files.iter()
+        .map(|f| (&f.path[..], f)).collect::<HashMap<_, _>>();
+    let new_files = new.files.iter()
+        .map(|f| (&f.path[..], f)).collect::<HashMap<_, _>>();
+    for file in &old.files {
+        match new_files.get(&file.path[..]) {
+            Some(new_file) => {
+                let lines = changes(&file_fields(file),
+                                    &file_fields(new_file));
+                if lines.len() > 0 {
+                    result.files.push(format!("~ {}: {}",
+                        file.path, lines.join(", ")));
+                }
+            }
+            None => result.files.push(format!("- {}", file.path)),
+        }
+    }
+    for file in &new.files {
+        if !old_files.contains_key(&file.path[..]) {
+            result.files.push(format!("+ {}", file.path));
+        }
+    }
+    result
+}
+
+fn _diff(old: &Path, new: &Path) -> Result<bool, Error> {
+    let old_pkg = read_package(old)
+        .map_err(|e| format_err!("can't read {:?}: {}", old, e))?;
+    let new_pkg = read_package(new)
+        .map_err(|e| format_err!("can't read {:?}: {}", new, e))?;
+    let diff = diff_packages(&old_pkg, &new_pkg);
+    let sections = [
+        ("Control", &diff.control),
+        ("Members", &diff.members),
+        ("Files", &diff.files),
+    ];
+    let mut same = true;
+    for &(title, lines) in &sections {
+        if lines.len() > 0 {
+            println!("{}:", title);
+            for line in lines {
+                println!("  {}", line);
+            }
+            same = false;
+        }
+    }
+    Ok(same)
+}
+
+
+pub fn diff(args: Vec<String>) {
+    let mut old = PathBuf::new();
+    let mut new = PathBuf::new();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.refer(&mut old)
+            .add_argument("old", Parse, "Old package file")
+            .required();
+        ap.refer(&mut new)
+            .add_argument("new", Parse, "New package file")
+            .required();
+        match ap.parse(args, &mut stdout(), &mut stderr()) {
+            Ok(()) => {}
+            Err(x) => exit(x),
+        }
+    }
+
+    // Exit codes are the same as of diff(1)
+    match _diff(&old, &new) {
+        Ok(true) => {}
+        Ok(false) => exit(1),
+        Err(err) => {
+            writeln!(&mut stderr(), "Error: {}", err).ok();
+            exit(2);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use repo::inspect::{Package, Member, FileEntry};
+    use super::diff_packages;
+
+    fn file(path: &str, kind: &'static str, mode: &str) -> FileEntry {
+        FileEntry {
+            path: path.to_string(),
+            kind: kind,
+            mode: mode.to_string(),
+            user: String::from("root"),
+            group: String::from("root"),
+            uid: 0,
+            gid: 0,
+            size: None,
+            device: None,
+            link: None,
+            md5: None,
+        }
+    }
+
+    fn regular(path: &str, mode: &str, md5: &str) -> FileEntry {
+        FileEntry {
+            size: Some(5),
+            md5: Some(md5.to_string()),
+            .. file(path, "file", mode)
+        }
+    }
+
+    fn member(name: &str, size: u64, compression: Option<&str>) -> Member {
+        Member {
+            name: name.to_string(),
+            size: size,
+            mtime: 0,
+            uid: 0,
+            gid: 0,
+            mode: String::from("100644"),
+            compression: compression.map(|x| x.to_string()),
+        }
+    }
+
+    fn package(version: &str, files: Vec<FileEntry>) -> Package {
+        let mut control = BTreeMap::new();
+        control.insert(String::from("Package"), String::from("hello"));
+        control.insert(String::from("Version"), version.to_string());
+        Package {
+            control: control,
+            members: Vec::new(),
+            files: files,
+            control_text: String::new(),
+        }
+    }
+
+    #[test]
+    fn same() {
+        let diff = diff_packages(
+            &package("1.0", vec![file("/usr", "dir", "0755")]),
+            &package("1.0", vec![file("/usr", "dir", "0755")]));
+        assert_eq!(diff.control.len(), 0);
+        assert_eq!(diff.members.len(), 0);
+        assert_eq!(diff.files.len(), 0);
+    }
+
+    #[test]
+    fn changes() {
+        let diff = diff_packages(
+            &package("1.0", vec![
+                file("/usr", "dir", "0755"),
+                regular("/usr/hello", "0644", "aaa"),
+                regular("/usr/old", "0644", "bbb"),
+            ]),
+            &package("1.1", vec![
+                file("/usr", "dir", "0755"),
+                regular("/usr/hello", "0755", "ccc"),
+                regular("/usr/new", "0644", "bbb"),
+            ]));
+        assert_eq!(diff.control, vec![r#"~ Version: "1.0" -> "1.1""#]);
+        assert_eq!(diff.files, vec![
+            "~ /usr/hello: mode 0644 -> 0755, md5 aaa -> ccc",
+            "- /usr/old",
+            "+ /usr/new",
+        ]);
+    }
+
+    #[test]
+    fn members() {
+        let mut old = package("1.0", Vec::new());
+        old.members = vec![
+            member("debian-binary", 4, None),
+            member("control.tar.gz", 500, Some("gzip")),
+            member("data.tar.gz", 1000, Some("gzip")),
+            member("_gpgorigin", 100, None),
+        ];
+        let mut new = package("1.0", Vec::new());
+        new.members = vec![
+            member("debian-binary", 4, None),
+            member("control.tar.gz", 510, Some("gzip")),
+            member("data.tar.xz", 800, Some("xz")),
+        ];
+        let diff = diff_packages(&old, &new);
+        assert_eq!(diff.members, vec![
+            "~ control.tar.gz: size 500 -> 510",
+            "~ data.tar.gz: size 1000 -> 800, compression gzip -> xz",
+            "- _gpgorigin",
+        ]);
+    }
+}
@@ -0,0 +1,275 @@
+use std::io::{self, stdout, stderr, Read, Write, BufReader};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::collections::BTreeMap;
+
+use argparse::{ArgumentParser, Parse, StoreTrue};
+use failure::{Error, err_msg};
+use serde_json;
+use tar;
+
+use hash_file::Md5Reader;
+use pack::compress::Decoder;
+use repo::ar;
+use repo::deb::parse_control;
+
+
+/// Member of the `ar` archive
+#[derive(Serialize)]
+pub struct Member {
+    pub name: String,
+    pub size: u64,
+    pub mtime: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: String,
+    /// Compression of tarballs, `None` for other members
+    pub compression: Option<String>,
+}
+
+/// Entry of `data.tar`
+#[derive(Serialize)]
+pub struct FileEntry {
+    pub path: String,
+    #[serde(rename="type")]
+    pub kind: &'static str,
+    pub mode: String,
+    pub user: String,
+    pub group: String,
+    pub uid: u64,
+    pub gid: u64,
+    pub size: Option<u64>,
+    /// Device numbers (`major,minor`)
+    pub device: Option<String>,
+    /// Target of a symlink or a hard link
+    pub link: Option<String>,
+    /// Checksum of contents of regular files
+    pub md5: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Package {
+    pub control: BTreeMap<String, String>,
+    pub members: Vec<Member>,
+    pub files: Vec<FileEntry>,
+    #[serde(skip)]
+    pub control_text: String,
+}
+
+
+fn tar_path(path: &Path) -> String {
+    let path = path.strip_prefix("./").unwrap_or(path);
+    format!("/{}", path.display())
+}
+
+fn read_control<R: Read>(input: R, pkg: &mut Package) -> Result<(), Error> {
+    let mut arch = tar::Archive::new(input);
+    for entry in arch.entries()? {
+        let mut entry = entry?;
+        if tar_path(&entry.path()?) == "/control" {
+            entry.read_to_string(&mut pkg.control_text)?;
+            let mut control = parse_control(pkg.control_text.as_bytes())?;
+            if control.len() != 1 {
+                return Err(err_msg("Wrong control file in package"));
+            }
+            pkg.control = control.pop().unwrap().into_iter()
+                .map(|(k, v)| ((*k).clone(), v))
+                .collect();
+        }
+    }
+    Ok(())
+}
+
+fn read_files<R: Read>(input: R, pkg: &mut Package) -> Result<(), Error> {
+    let mut arch = tar::Archive::new(input);
+    for entry in arch.entries()? {
+        let entry = entry?;
+        let mut file = file_entry(&entry)?;
+        if file.kind == "file" {
+            let mut reader = Md5Reader::new(entry);
+            io::copy(&mut reader, &mut io::sink())?;
+            file.md5 = Some(format!("{:x}", reader.digest()));
+        }
+        pkg.files.push(file);
+    }
+    Ok(())
+}
+
+fn file_entry<R: Read>(entry: &tar::Entry<R>) -> io::Result<FileEntry> {
+    let head = entry.header();
+    let path = tar_path(&entry.path()?);
+    // Directories are stored with a trailing slash
+    let path = if path.len() > 1 {
+        path.trim_end_matches('/').to_string()
+    } else {
+        path
+    };
+    let link = entry.link_name()?.map(|x| x.display().to_string());
+    let device = || -> io::Result<Option<String>> {
+        Ok(Some(format!("{},{}",
+            head.device_major()?.unwrap_or(0),
+            head.device_minor()?.unwrap_or(0))))
+    };
+    let typ = head.entry_type();
+    let (kind, size, device, link) = if typ.is_hard_link() {
+        ("hardlink", Some(head.size()?), None,
+         link.map(|x| tar_path(Path::new(&x))))
+    } else if typ.is_symlink() {
+        ("symlink", None, None, link)
+    } else if typ.is_dir() {
+        ("dir", None, None, None)
+    } else if typ.is_fifo() {
+        ("fifo", None, None, None)
+    } else if typ.is_character_special() {
+        ("char", None, device()?, None)
+    } else if typ.is_block_special() {
+        ("block", None, device()?, None)
+    } else {
+        ("file", Some(head.size()?), None, None)
+    };
+    // Numeric fields may be empty
+    let uid = head.uid().unwrap_or(0) as u64;
+    let gid = head.gid().unwrap_or(0) as u64;
+    Ok(FileEntry {
+        path: path,
+        kind: kind,
+        mode: format!("{:04o}", head.mode().unwrap_or(0)),
+        user: head.username().ok().and_then(|x| x)
+            .filter(|x| x.len() > 0)
+            .map(|x| x.to_string()).unwrap_or(uid.to_string()),
+        group: head.groupname().ok().and_then(|x| x)
+            .filter(|x| x.len() > 0)
+            .map(|x| x.to_string()).unwrap_or(gid.to_string()),
+        uid: uid,
+        gid: gid,
+        size: size,
+        device: device,
+        link: link,
+        md5: None,
+    })
+}
+
+/// Reads control fields, archive members and files of a debian package
+pub fn read_package(path: &Path) -> Result<Package, Error> {
+    let mut pkg = Package {
+        control: BTreeMap::new(),
+        members: Vec::new(),
+        files: Vec::new(),
+        control_text: String::new(),
+    };
+    let mut arch = ar::Archive::new(BufReader::new(File::open(path)?))?;
+    while let Some((head, member)) = arch.next_member()? {
+        let compression = if head.name.starts_with("control.tar") ||
+            head.name.starts_with("data.tar")
+        {
+            let mut input = Decoder::new(BufReader::new(member))?;
+            let compression = format!("{:?}", input.compression());
+            if head.name.starts_with("control.tar") {
+                read_control(&mut input, &mut pkg)?;
+            } else {
+                read_files(&mut input, &mut pkg)?;
+            }
+            Some(compression)
+        } else {
+            None
+        };
+        pkg.members.push(Member {
+            name: head.name,
+            size: head.size,
+            mtime: head.mtime,
+            uid: head.uid,
+            gid: head.gid,
+            mode: format!("{:o}", head.mode),
+            compression: compression,
+        });
+    }
+    if pkg.control_text.len() == 0 {
+        return Err(err_msg("No control file found in package"));
+    }
+    Ok(pkg)
+}
+
+fn print_package(pkg: &Package) {
+    println!("Control:");
+    for line in pkg.control_text.lines() {
+        println!(" {}", line);
+    }
+    println!("Members:");
+    for member in &pkg.members {
+        match member.compression {
+            Some(ref compression) => {
+                println!("  {:<20} {:>10} {}", member.name, member.size,
+                         compression);
+            }
+            None => println!("  {:<20} {:>10}", member.name, member.size),
+        }
+    }
+    println!("Files:");
+    // Same format as `bulk pack --list`
+    for file in &pkg.files {
+        let typ = match file.kind {
+            "hardlink" => 'h',
+            "symlink" => 'l',
+            "dir" => 'd',
+            "fifo" => 'p',
+            "char" => 'c',
+            "block" => 'b',
+            _ => '-',
+        };
+        let suffix = match (file.kind, &file.link) {
+            ("hardlink", &Some(ref target)) => format!(" link to {}", target),
+            (_, &Some(ref link)) => format!(" -> {}", link),
+            (_, &None) => String::new(),
+        };
+        let size = match (file.size, &file.device) {
+            (Some(size), _) => size.to_string(),
+            (None, &Some(ref device)) => device.clone(),
+            (None, &None) => String::new(),
+        };
+        println!("{} {} {:<12} {:>10} {}{}", typ, file.mode,
+            format!("{}/{}", file.user, file.group), size, file.path,
+            suffix);
+    }
+}
+
+fn _inspect(path: &Path, json: bool) -> Result<(), Error> {
+    let pkg = read_package(path)
+        .map_err(|e| format_err!("can't read {:?}: {}", path, e))?;
+    if json {
+        let out = stdout();
+        let mut out = out.lock();
+        serde_json::to_writer_pretty(&mut out, &pkg)?;
+        writeln!(&mut out, "")?;
+    } else {
+        print_package(&pkg);
+    }
+    Ok(())
+}
+
+
+pub fn inspect(args: Vec<String>) {
+    let mut package = PathBuf::new();
+    let mut json = false;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.refer(&mut json)
+            .add_option(&["--json"], StoreTrue,
+                "Print package info as JSON");
+        ap.refer(&mut package)
+            .add_argument("package", Parse, "Package file to inspect")
+            .required();
+        match ap.parse(args, &mut stdout(), &mut stderr()) {
+            Ok(()) => {}
+            Err(x) => exit(x),
+        }
+    }
+
+    match _inspect(&package, json) {
+        Ok(()) => {}
+        Err(err) => {
+            writeln!(&mut stderr(), "Error: {}", err).ok();
+            exit(1);
+        }
+    }
+}
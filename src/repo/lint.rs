@@ -0,0 +1,376 @@
+use std::io::{stdout, stderr, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use argparse::{ArgumentParser, ParseOption, Collect};
+use failure::Error;
+
+use config::{Config, Severity};
+use repo::inspect::{read_package, Package};
+
+
+/// Names of the checks and their default severities
+const CHECKS: &[(&str, Severity)] = &[
+    ("package-name", Severity::error),
+    ("version", Severity::error),
+    ("maintainer", Severity::warning),
+    ("description", Severity::warning),
+    ("fhs", Severity::warning),
+    ("world-writable", Severity::warning),
+    ("setuid", Severity::warning),
+    ("absolute-symlink", Severity::warning),
+];
+
+/// Top-level directories of Filesystem Hierarchy Standard, with allowed
+/// subdirectories for some of them
+const FHS: &[(&str, &[&str])] = &[
+    ("bin", &[]),
+    ("boot", &[]),
+    ("etc", &[]),
+    ("lib", &[]),
+    ("lib32", &[]),
+    ("lib64", &[]),
+    ("libx32", &[]),
+    ("opt", &[]),
+    ("sbin", &[]),
+    ("srv", &[]),
+    ("usr", &["bin", "games", "include", "lib", "lib32", "lib64", "libexec",
+              "libx32", "local", "sbin", "share", "src"]),
+    ("var", &["backups", "cache", "crash", "games", "lib", "local", "lock",
+              "log", "mail", "opt", "run", "spool", "tmp"]),
+];
+
+pub struct Problem {
+    pub check: &'static str,
+    pub severity: Severity,
+    pub message: String,
+}
+
+pub type Severities = HashMap<&'static str, Severity>;
+
+
+/// Returns severities of all checks, overridden by ones in config
+pub fn severities(config: &BTreeMap<String, Severity>)
+    -> Result<Severities, String>
+{
+    let mut result = CHECKS.iter().cloned().collect::<Severities>();
+    for (name, &severity) in config {
+        match CHECKS.iter().find(|&&(check, _)| check == name) {
+            Some(&(check, _)) => {
+                result.insert(check, severity);
+            }
+            None => {
+                return Err(format!("Unknown lint check {:?}, known checks \
+                    are: {}", name, CHECKS.iter().map(|&(x, _)| x)
+                        .collect::<Vec<_>>().join(", ")));
+            }
+        }
+    }
+    Ok(result)
+}
+
+fn valid_name(name: &str) -> bool {
+    name.len() >= 2 &&
+    name.chars().all(|c| {
+        c.is_ascii_lowercase() || c.is_ascii_digit() ||
+        c == '+' || c == '-' || c == '.'
+    }) &&
+    name.chars().next().map(|c| c.is_ascii_alphanumeric()).unwrap_or(false)
+}
+
+/// Checks version in `[epoch:]upstream[-revision]` format
+///
+/// Upstream version may contain colons if there is an epoch, so only
+/// the first colon delimits the epoch.
+fn valid_version(version: &str) -> bool {
+    let (epoch, rest) = match version.find(':') {
+        Some(pos) => (&version[..pos], &version[pos+1..]),
+        None => ("0", version),
+    };
+    let (upstream, revision) = match rest.rfind('-') {
+        Some(pos) => (&rest[..pos], &rest[pos+1..]),
+        None => (rest, "0"),
+    };
+    let allowed = |c: char| {
+        c.is_ascii_alphanumeric() || c == '.' || c == '+' || c == '~'
+    };
+    epoch.len() > 0 && epoch.chars().all(|c| c.is_ascii_digit()) &&
+    upstream.chars().next().map(|c| c.is_ascii_digit()).unwrap_or(false) &&
+    upstream.chars().all(|c| allowed(c) || c == '-' || c == ':') &&
+    revision.len() > 0 && revision.chars().all(allowed)
+}
+
+/// Returns true if file at `path` is at a standard location
+///
+/// Directories are allowed anywhere under standard directories (e.g.
+/// empty `/usr/local/bin`), files aren't allowed in `/usr/local`.
+fn fhs_location(path: &str, is_dir: bool) -> bool {
+    let mut parts = path.trim_start_matches('/').split('/');
+    let top = parts.next().unwrap_or("");
+    if top == "" {
+        return true;
+    }
+    let subdirs = match FHS.iter().find(|&&(dir, _)| dir == top) {
+        Some(&(_, subdirs)) => subdirs,
+        None => return false,
+    };
+    match parts.next() {
+        Some("local") if top == "usr" => is_dir,
+        Some(sub) if subdirs.len() > 0 => subdirs.contains(&sub),
+        // Files directly in `/usr` aren't standard too
+        None if subdirs.len() > 0 => is_dir,
+        _ => true,
+    }
+}
+
+/// Runs all checks on the package, ignored problems are not returned
+pub fn check(pkg: &Package, severities: &Severities) -> Vec<Problem> {
+    let mut result = Vec::new();
+    {
+        let mut report = |check: &'static str, message: String| {
+            let severity = severities.get(check).cloned()
+                .unwrap_or(Severity::error);
+            if severity != Severity::ignore {
+                result.push(Problem {
+                    check: check,
+                    severity: severity,
+                    message: message,
+                });
+            }
+        };
+        let field = |name: &str| {
+            pkg.control.get(name).map(|x| x.trim()).unwrap_or("")
+        };
+
+        let name = field("Package");
+        if !valid_name(name) {
+            report("package-name", format!("invalid package name {:?}, \
+                at least two lowercase letters, digits, `+`, `-` or `.` \
+                starting with a letter or digit expected", name));
+        }
+        let version = field("Version");
+        if !valid_version(version) {
+            report("version", format!("invalid version {:?}, \
+                `[epoch:]upstream[-revision]` expected, upstream version \
+                must start with a digit", version));
+        }
+        if field("Maintainer") == "" {
+            report("maintainer", format!("no Maintainer field"));
+        }
+        if field("Description").lines().next().unwrap_or("").trim() == "" {
+            report("description", format!("no Description field"));
+        }
+
+        let paths = pkg.files.iter().map(|f| &f.path[..])
+            .collect::<HashSet<_>>();
+        let mut nonstandard = Vec::<&str>::new();
+        for file in &pkg.files {
+            let mode = u32::from_str_radix(&file.mode, 8).unwrap_or(0);
+            let is_dir = file.kind == "dir";
+            // Only the topmost of nonstandard directories is reported
+            if !fhs_location(&file.path, is_dir) &&
+                !nonstandard.iter().any(|d| {
+                    file.path.starts_with(d) &&
+                    file.path[d.len()..].starts_with('/')
+                })
+            {
+                report("fhs", format!("{} is outside of standard \
+                    directories", file.path));
+                nonstandard.push(&file.path);
+            }
+            // Sticky directories like `/tmp` are fine
+            if mode & 0o002 != 0 && file.kind != "symlink" &&
+                !(is_dir && mode & 0o1000 != 0)
+            {
+                report("world-writable", format!("{} is world-writable \
+                    (mode {})", file.path, file.mode));
+            }
+            if mode & 0o6000 != 0 && !is_dir {
+                report("setuid", format!("{} is {} (mode {})", file.path,
+                    if mode & 0o4000 != 0 { "setuid" } else { "setgid" },
+                    file.mode));
+            }
+            if let (true, Some(ref link)) = (file.kind == "symlink",
+                                             file.link.as_ref())
+            {
+                let target = link.trim_end_matches('/');
+                if link.starts_with('/') && !paths.contains(target) {
+                    report("absolute-symlink", format!("{} points to {} \
+                        which is not in the package", file.path, link));
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Checks package file and prints problems found, returns false if there
+/// are errors
+pub fn lint_file(path: &Path, severities: &Severities)
+    -> Result<bool, Error>
+{
+    let pkg = read_package(path)
+        .map_err(|e| format_err!("can't read {:?}: {}", path, e))?;
+    let mut ok = true;
+    for problem in check(&pkg, severities) {
+        writeln!(&mut stderr(), "{}: {:?}: {} [{}]", path.display(),
+                 problem.severity, problem.message, problem.check).ok();
+        if problem.severity == Severity::error {
+            ok = false;
+        }
+    }
+    Ok(ok)
+}
+
+fn _lint(config: Option<PathBuf>, packages: &[PathBuf])
+    -> Result<bool, Error>
+{
+    // Config is optional, unless specified explicitly
+    let config = config.or_else(|| {
+        Some(PathBuf::from("bulk.yaml")).filter(|x| x.exists())
+    });
+    let lint = match config {
+        Some(ref path) => Config::parse_file(path)
+            .map_err(|e| format_err!("can't parse config {:?}: {}", path, e))?
+            .lint,
+        None => BTreeMap::new(),
+    };
+    let severities = severities(&lint).map_err(|e| format_err!("{}", e))?;
+    let mut ok = true;
+    for path in packages {
+        ok &= lint_file(path, &severities)?;
+    }
+    Ok(ok)
+}
+
+
+pub fn lint(args: Vec<String>) {
+    let mut config = None;
+    let mut packages = Vec::<PathBuf>::new();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.refer(&mut config)
+            .add_option(&["-c", "--config"], ParseOption,
+                "Package configuration file with `lint` section. By default \
+                 `bulk.yaml` is used if it exists.");
+        ap.refer(&mut packages)
+            .add_argument("packages", Collect, "Package files to check")
+            .required();
+        match ap.parse(args, &mut stdout(), &mut stderr()) {
+            Ok(()) => {}
+            Err(x) => exit(x),
+        }
+    }
+
+    match _lint(config, &packages) {
+        Ok(true) => {}
+        Ok(false) => exit(1),
+        Err(err) => {
+            writeln!(&mut stderr(), "Error: {}", err).ok();
+            exit(1);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use config::Severity;
+    use repo::inspect::{Package, FileEntry};
+    use super::{valid_name, valid_version, fhs_location, check, severities};
+
+    #[test]
+    fn names() {
+        assert!(valid_name("libfoo1"));
+        assert!(valid_name("g++-12"));
+        assert!(!valid_name("Foo"));
+        assert!(!valid_name("x"));
+        assert!(!valid_name("-foo"));
+        assert!(!valid_name("foo_bar"));
+    }
+
+    #[test]
+    fn versions() {
+        assert!(valid_version("1.0"));
+        assert!(valid_version("1:2.3~rc1-0ubuntu1"));
+        assert!(valid_version("1.0-beta-1"));
+        assert!(!valid_version("v1.0"));
+        assert!(!valid_version("1.0-"));
+        assert!(!valid_version("a:1.0"));
+        assert!(!valid_version("1.0_1"));
+        assert!(valid_version("1:2:3"));
+        assert!(valid_version("1:2:3-4"));
+        assert!(!valid_version("1.0:1"));
+        assert!(!valid_version(":1.0"));
+        assert!(!valid_version(""));
+    }
+
+    #[test]
+    fn fhs() {
+        assert!(fhs_location("/", true));
+        assert!(fhs_location("/usr/share/doc", true));
+        assert!(fhs_location("/etc/foo.conf", false));
+        assert!(fhs_location("/usr/local/bin", true));
+        assert!(!fhs_location("/usr/local/bin/foo", false));
+        assert!(!fhs_location("/usr/foo", true));
+        assert!(!fhs_location("/home/user", true));
+        assert!(!fhs_location("/foo", false));
+    }
+
+    fn file(path: &str, kind: &'static str, mode: &str, link: Option<&str>)
+        -> FileEntry
+    {
+        FileEntry {
+            path: path.to_string(),
+            kind: kind,
+            mode: mode.to_string(),
+            user: String::from("root"),
+            group: String::from("root"),
+            uid: 0,
+            gid: 0,
+            size: None,
+            device: None,
+            link: link.map(|x| x.to_string()),
+            md5: None,
+        }
+    }
+
+    #[test]
+    fn package() {
+        let mut control = BTreeMap::new();
+        control.insert(String::from("Package"), String::from("hello"));
+        control.insert(String::from("Version"), String::from("1.0"));
+        let pkg = Package {
+            control: control,
+            members: Vec::new(),
+            files: vec![
+                file("/tmp", "dir", "1777", None),
+                file("/opt2", "dir", "0755", None),
+                file("/opt2/x", "file", "0666", None),
+                file("/usr/bin/su", "file", "4755", None),
+                file("/usr/bin/sh", "symlink", "0777", Some("/bin/dash")),
+                file("/usr/bin/sh2", "symlink", "0777", Some("/usr/bin/su")),
+            ],
+            control_text: String::new(),
+        };
+        let mut config = BTreeMap::new();
+        config.insert(String::from("maintainer"), Severity::ignore);
+        config.insert(String::from("setuid"), Severity::error);
+        let problems = check(&pkg, &severities(&config).unwrap());
+        assert_eq!(problems.iter()
+            .map(|p| (p.check, p.severity)).collect::<Vec<_>>(), vec![
+                ("description", Severity::warning),
+                ("fhs", Severity::warning),
+                ("fhs", Severity::warning),
+                ("world-writable", Severity::warning),
+                ("setuid", Severity::error),
+                ("absolute-symlink", Severity::warning),
+            ]);
+        assert_eq!(problems[2].message,
+                   "/opt2 is outside of standard directories");
+        config.insert(String::from("unknown"), Severity::ignore);
+        assert!(severities(&config).is_err());
+    }
+}
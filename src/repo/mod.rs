@@ -2,6 +2,10 @@ mod metadata;
 mod ar;
 mod deb;
 mod debian;
+mod inspect;
+mod unpack;
+mod diff;
+pub mod lint;
 
 use std::io::{stdout, stderr, Write};
 use std::path::{Path, PathBuf};
@@ -14,6 +18,11 @@ use argparse::{ArgumentParser, Parse, Collect, StoreConst};
 use config::{Config, RepositoryType};
 use repo::metadata::gather_metadata;
 
+pub use self::inspect::inspect;
+pub use self::unpack::unpack;
+pub use self::diff::diff;
+pub use self::lint::lint;
+
 
 fn _repo_add(config: &Path, packages: &Vec<String>, dir: &Path,
     on_conflict: debian::ConflictResolution)
@@ -0,0 +1,140 @@
+use std::io::{stdout, stderr, Read, Write, BufReader};
+use std::fs::{File, Permissions, create_dir_all, set_permissions};
+use std::path::{Path, PathBuf, Component};
+use std::process::exit;
+use std::os::unix::fs::PermissionsExt;
+
+use argparse::{ArgumentParser, Parse, StoreTrue};
+use failure::Error;
+use tar;
+
+use pack::compress::Decoder;
+use repo::ar;
+
+
+/// Checks that path in archive is relative and has no `..`, so it can't
+/// point outside of the target directory
+fn check_path(path: &Path) -> Result<(), Error> {
+    for component in path.components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_)
+            => {
+                return Err(format_err!("refusing to unpack {:?}: \
+                    path points outside of the target directory", path));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn unpack_tar<R: Read>(input: R, dir: &Path) -> Result<(), Error> {
+    create_dir_all(dir)?;
+    let mut arch = tar::Archive::new(input);
+    let mut dirs = Vec::new();
+    for entry in arch.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        check_path(&path)?;
+        let kind = entry.header().entry_type();
+        // Tar crate would write them as regular files
+        if kind.is_fifo() || kind.is_character_special() ||
+            kind.is_block_special()
+        {
+            writeln!(&mut stderr(), "Warning: skipping special file {:?}",
+                     path).ok();
+            continue;
+        }
+        if kind.is_hard_link() {
+            if let Some(target) = entry.link_name()? {
+                check_path(&target)?;
+            }
+        }
+        // Also checks that no symlink in the package leads outside of `dir`
+        entry.set_preserve_permissions(true);
+        entry.unpack_in(dir)
+            .map_err(|e| format_err!("can't unpack {:?}: {}", path, e))?;
+        if kind.is_dir() {
+            dirs.push((dir.join(&path), entry.header().mode()?));
+        }
+    }
+    // Directories may be read-only, so change their mode after everything
+    // is written into them
+    for (path, mode) in dirs.into_iter().rev() {
+        set_permissions(&path, Permissions::from_mode(mode & 0o7777))?;
+    }
+    Ok(())
+}
+
+fn _unpack(path: &Path, dir: &Path, control: bool) -> Result<(), Error> {
+    let mut arch = ar::Archive::new(BufReader::new(File::open(path)?))?;
+    let mut data = false;
+    while let Some((head, mut member)) = arch.next_member()? {
+        if head.name == "debian-binary" {
+            let mut buf = String::with_capacity(4);
+            member.read_to_string(&mut buf)?;
+            if !buf.starts_with("2.") {
+                return Err(format_err!("unsupported deb format {:?}",
+                                       buf.trim()));
+            }
+        } else if head.name.starts_with("control.tar") && control {
+            unpack_tar(Decoder::new(BufReader::new(member))?,
+                       &dir.join("DEBIAN"))?;
+        } else if head.name.starts_with("data.tar") {
+            unpack_tar(Decoder::new(BufReader::new(member))?, dir)?;
+            data = true;
+        }
+    }
+    if !data {
+        return Err(format_err!("no data.tar found in {:?}", path));
+    }
+    Ok(())
+}
+
+
+pub fn unpack(args: Vec<String>) {
+    let mut package = PathBuf::new();
+    let mut dir = PathBuf::new();
+    let mut control = false;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.refer(&mut dir)
+            .add_option(&["-d", "--dir"], Parse,
+                "Directory to unpack files to")
+            .required();
+        ap.refer(&mut control)
+            .add_option(&["--control"], StoreTrue,
+                "Also write control files (`control`, scripts, etc.) into \
+                 DEBIAN subdirectory of the target directory");
+        ap.refer(&mut package)
+            .add_argument("package", Parse, "Package file to unpack")
+            .required();
+        match ap.parse(args, &mut stdout(), &mut stderr()) {
+            Ok(()) => {}
+            Err(x) => exit(x),
+        }
+    }
+
+    match _unpack(&package, &dir, control) {
+        Ok(()) => {}
+        Err(err) => {
+            writeln!(&mut stderr(), "Error: {}", err).ok();
+            exit(1);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use super::check_path;
+
+    #[test]
+    fn paths() {
+        assert!(check_path(Path::new("./usr/bin/hello")).is_ok());
+        assert!(check_path(Path::new("./")).is_ok());
+        assert!(check_path(Path::new("./usr/../../etc/passwd")).is_err());
+        assert!(check_path(Path::new("/etc/passwd")).is_err());
+    }
+}